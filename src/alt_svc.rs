@@ -0,0 +1,402 @@
+//! Alternative services, see [RFC 7838](https://datatracker.ietf.org/doc/html/rfc7838).
+
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::HttpVersion;
+
+/// Freshness lifetime of an alternative service without `ma` parameter.
+///
+/// > When omitted, its default value is 86400 (24 hours).
+///
+/// <https://datatracker.ietf.org/doc/html/rfc7838#section-3.1>
+pub const DEFAULT_ALT_SVC_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Upper bound of the freshness lifetime of an alternative service.
+///
+/// Larger `ma` values, including ones overflowing an integer, are capped, so
+/// that a server cannot pin an alternative service indefinitely.
+pub const MAX_ALT_SVC_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Alternative service information from previous connections.
+///
+/// Parse it from an `Alt-Svc` header field via [`parse_alt_svc_header`], or
+/// from an ALTSVC frame via [`parse_h2_altsvc_frame`] and
+/// [`parse_h3_altsvc_frame`].
+///
+/// See [RFC 7838](https://datatracker.ietf.org/doc/html/rfc7838).
+#[derive(Debug, Clone, PartialEq)]
+pub struct AltSvc {
+    /// Host of the alternative service. [`None`] for the origin's host.
+    ///
+    /// A different host is resolved separately, see
+    /// [`Endpoint::origin_host`](crate::Endpoint::origin_host).
+    pub host: Option<String>,
+    /// Port of the alternative service. [`None`] for the origin's port.
+    pub port: Option<u16>,
+    pub protocol: HttpVersion,
+    /// When the alternative service expires. [`None`] never expires.
+    ///
+    /// Expired alternative services are dropped when a race starts.
+    pub expires: Option<Instant>,
+    /// Whether the alternative service survives a network change.
+    ///
+    /// <https://datatracker.ietf.org/doc/html/rfc7838#section-3.1>
+    pub persist: bool,
+}
+
+impl AltSvc {
+    /// Whether the alternative service is hosted at a different host or port
+    /// than the origin.
+    pub(crate) fn is_elsewhere(&self, origin_host: &str, origin_port: u16) -> bool {
+        self.host.as_deref().is_some_and(|host| host != origin_host)
+            || self.port.is_some_and(|port| port != origin_port)
+    }
+
+    pub(crate) fn is_expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Alternative services advertised by an origin.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AltSvcUpdate {
+    /// Replaces all previously advertised alternative services.
+    ///
+    /// Alternatives with unsupported protocols are left out, thus the list
+    /// might be empty.
+    Alternatives(Vec<AltSvc>),
+    /// Invalidates all previously advertised alternative services.
+    ///
+    /// <https://datatracker.ietf.org/doc/html/rfc7838#section-3>
+    Clear,
+}
+
+/// Payload of an ALTSVC frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AltSvcFrame {
+    /// Origin the alternative services apply to. [`None`] when sent on a
+    /// request stream, in which case they apply to the origin of the request.
+    pub origin: Option<String>,
+    pub update: AltSvcUpdate,
+}
+
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub struct AltSvcParseError {
+    inner: AltSvcParseErrorInner,
+}
+
+impl From<AltSvcParseErrorInner> for AltSvcParseError {
+    fn from(inner: AltSvcParseErrorInner) -> Self {
+        Self { inner }
+    }
+}
+
+#[derive(Error, Debug)]
+enum AltSvcParseErrorInner {
+    #[error("unexpected character at offset {0}")]
+    UnexpectedCharacter(usize),
+    #[error("unexpected end of input")]
+    UnexpectedEnd,
+    #[error("invalid alt-authority: {0}")]
+    InvalidAuthority(String),
+    #[error("truncated frame")]
+    TruncatedFrame,
+    #[error("field value is not valid UTF-8")]
+    InvalidUtf8,
+}
+
+/// Parses an `Alt-Svc` header field value, e.g. `h3=":443"; ma=3600, h2="alt.example.com:443"`.
+///
+/// The expiry of each alternative is derived from its `ma` parameter relative
+/// to `now`.
+///
+/// <https://datatracker.ietf.org/doc/html/rfc7838#section-3>
+pub fn parse_alt_svc_header(value: &str, now: Instant) -> Result<AltSvcUpdate, AltSvcParseError> {
+    if value.trim().eq_ignore_ascii_case("clear") {
+        return Ok(AltSvcUpdate::Clear);
+    }
+
+    let mut parser = Parser {
+        input: value,
+        pos: 0,
+    };
+    let mut alternatives = Vec::new();
+    loop {
+        parser.skip_whitespace();
+        if parser.eat(',') {
+            // Empty list element.
+            continue;
+        }
+        if parser.is_done() {
+            break;
+        }
+
+        if let Some(alt_svc) = parser.alternative(now)? {
+            alternatives.push(alt_svc);
+        }
+
+        parser.skip_whitespace();
+        if !parser.is_done() && !parser.eat(',') {
+            return Err(parser.unexpected());
+        }
+    }
+
+    Ok(AltSvcUpdate::Alternatives(alternatives))
+}
+
+/// Parses the payload of an HTTP/2 ALTSVC frame.
+///
+/// ```text
+/// +-------------------------------+-------------------------------+
+/// |         Origin-Len (16)       | Origin? (*)                 ...
+/// +-------------------------------+-------------------------------+
+/// |                   Alt-Svc-Field-Value (*)                   ...
+/// +---------------------------------------------------------------+
+/// ```
+///
+/// <https://datatracker.ietf.org/doc/html/rfc7838#section-4>
+pub fn parse_h2_altsvc_frame(
+    payload: &[u8],
+    now: Instant,
+) -> Result<AltSvcFrame, AltSvcParseError> {
+    let [high, low, rest @ ..] = payload else {
+        return Err(AltSvcParseErrorInner::TruncatedFrame.into());
+    };
+    parse_altsvc_frame(usize::from(u16::from_be_bytes([*high, *low])), rest, now)
+}
+
+/// Parses the payload of an HTTP/3 ALTSVC frame.
+///
+/// Same as the HTTP/2 ALTSVC frame, though with a variable-length integer
+/// origin length.
+pub fn parse_h3_altsvc_frame(
+    payload: &[u8],
+    now: Instant,
+) -> Result<AltSvcFrame, AltSvcParseError> {
+    let (origin_len, rest) = decode_varint(payload)?;
+    let origin_len =
+        usize::try_from(origin_len).map_err(|_| AltSvcParseErrorInner::TruncatedFrame)?;
+    parse_altsvc_frame(origin_len, rest, now)
+}
+
+fn parse_altsvc_frame(
+    origin_len: usize,
+    rest: &[u8],
+    now: Instant,
+) -> Result<AltSvcFrame, AltSvcParseError> {
+    if rest.len() < origin_len {
+        return Err(AltSvcParseErrorInner::TruncatedFrame.into());
+    }
+    let (origin, value) = rest.split_at(origin_len);
+    let origin = std::str::from_utf8(origin).map_err(|_| AltSvcParseErrorInner::InvalidUtf8)?;
+    let value = std::str::from_utf8(value).map_err(|_| AltSvcParseErrorInner::InvalidUtf8)?;
+
+    Ok(AltSvcFrame {
+        origin: (!origin.is_empty()).then(|| origin.to_string()),
+        update: parse_alt_svc_header(value, now)?,
+    })
+}
+
+/// QUIC variable-length integer, see RFC 9000 Section 16.
+fn decode_varint(input: &[u8]) -> Result<(u64, &[u8]), AltSvcParseError> {
+    let first = *input.first().ok_or(AltSvcParseErrorInner::TruncatedFrame)?;
+    let len = 1 << (first >> 6);
+    if input.len() < len {
+        return Err(AltSvcParseErrorInner::TruncatedFrame.into());
+    }
+    let value = input[1..len]
+        .iter()
+        .fold(u64::from(first & 0x3f), |value, byte| {
+            (value << 8) | u64::from(*byte)
+        });
+    Ok((value, &input[len..]))
+}
+
+/// Maps an ALPN protocol ID to an HTTP version. [`None`] if unsupported.
+fn http_version(protocol_id: &str) -> Option<HttpVersion> {
+    match protocol_id {
+        "h3" => Some(HttpVersion::H3),
+        "h2" => Some(HttpVersion::H2),
+        "http/1.1" => Some(HttpVersion::H1),
+        _ => None,
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// ```text
+    /// alt-value = alternative *( OWS ";" OWS parameter )
+    /// alternative = protocol-id "=" alt-authority
+    /// ```
+    ///
+    /// Returns [`None`] for alternatives with unsupported protocols.
+    fn alternative(&mut self, now: Instant) -> Result<Option<AltSvc>, AltSvcParseError> {
+        let protocol_id = percent_decode(self.token()?);
+        self.expect('=')?;
+        let authority = self.quoted_string()?;
+        let (host, port) = parse_authority(&authority)?;
+
+        let mut max_age = DEFAULT_ALT_SVC_MAX_AGE;
+        let mut persist = false;
+        loop {
+            self.skip_whitespace();
+            if !self.eat(';') {
+                break;
+            }
+            self.skip_whitespace();
+            let name = self.token()?;
+            self.expect('=')?;
+            let value = if self.peek() == Some('"') {
+                self.quoted_string()?
+            } else {
+                self.token()?.to_string()
+            };
+
+            // Unknown parameters and invalid values are ignored.
+            if name.eq_ignore_ascii_case("ma") {
+                if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+                    let seconds = value.parse().unwrap_or(u64::MAX);
+                    max_age = Duration::from_secs(seconds).min(MAX_ALT_SVC_MAX_AGE);
+                }
+            } else if name.eq_ignore_ascii_case("persist") {
+                // > This specification only defines a single value for
+                // > "persist". Clients MUST ignore "persist" parameters with
+                // > values other than "1".
+                //
+                // <https://datatracker.ietf.org/doc/html/rfc7838#section-3.1>
+                persist |= value == "1";
+            }
+        }
+
+        Ok(http_version(&protocol_id).map(|protocol| AltSvc {
+            host,
+            port: Some(port),
+            protocol,
+            // Expires right away in the unlikely case of an overflow, rather
+            // than never.
+            expires: Some(now.checked_add(max_age).unwrap_or(now)),
+            persist,
+        }))
+    }
+
+    /// <https://datatracker.ietf.org/doc/html/rfc9110#section-5.6.2>
+    fn token(&mut self) -> Result<&'a str, AltSvcParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_tchar) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return Err(self.unexpected());
+        }
+        Ok(&self.input[start..self.pos])
+    }
+
+    /// <https://datatracker.ietf.org/doc/html/rfc9110#section-5.6.4>
+    fn quoted_string(&mut self) -> Result<String, AltSvcParseError> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            let c = self.next().ok_or(AltSvcParseErrorInner::UnexpectedEnd)?;
+            match c {
+                '"' => return Ok(value),
+                '\\' => value.push(self.next().ok_or(AltSvcParseErrorInner::UnexpectedEnd)?),
+                c => value.push(c),
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), AltSvcParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos == self.input.len()
+    }
+
+    fn unexpected(&self) -> AltSvcParseError {
+        if self.is_done() {
+            AltSvcParseErrorInner::UnexpectedEnd.into()
+        } else {
+            AltSvcParseErrorInner::UnexpectedCharacter(self.pos).into()
+        }
+    }
+}
+
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// > alt-authority = quoted-string ; containing [ uri-host ] ":" port
+///
+/// <https://datatracker.ietf.org/doc/html/rfc7838#section-3>
+fn parse_authority(authority: &str) -> Result<(Option<String>, u16), AltSvcParseError> {
+    let invalid = || AltSvcParseErrorInner::InvalidAuthority(authority.to_string());
+    let (host, port) = authority.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    Ok(((!host.is_empty()).then(|| host.to_string()), port))
+}
+
+/// > protocol-id = token ; percent-encoded ALPN protocol name
+///
+/// <https://datatracker.ietf.org/doc/html/rfc7838#section-3>
+fn percent_decode(input: &str) -> String {
+    let mut output = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next(), bytes.next()];
+            if let [Some(high), Some(low)] = hex
+                && let Ok(decoded) =
+                    u8::from_str_radix(std::str::from_utf8(&[high, low]).unwrap_or(""), 16)
+            {
+                output.push(decoded);
+                continue;
+            }
+            output.push(byte);
+            output.extend(hex.into_iter().flatten());
+        } else {
+            output.push(byte);
+        }
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
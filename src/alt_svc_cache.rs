@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
+
+use crate::{AltSvc, AltSvcUpdate, HttpVersion, Origin};
+
+/// Alternative services per origin, kept between races.
+///
+/// Feed it the alternatives parsed from `Alt-Svc` header fields and ALTSVC
+/// frames via [`AltSvcCache::update`] and pass the alternatives of an origin
+/// to a race via [`NetworkConfig::alt_svc`](crate::NetworkConfig::alt_svc).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AltSvcCache {
+    alternatives: HashMap<Origin, Vec<AltSvc>>,
+}
+
+impl AltSvcCache {
+    /// Applies alternatives advertised by `origin`.
+    ///
+    /// > When an Alt-Svc response header field is received from an origin,
+    /// > its value invalidates and replaces all cached alternative services
+    /// > for that origin.
+    ///
+    /// <https://datatracker.ietf.org/doc/html/rfc7838#section-3.1>
+    pub fn update(&mut self, origin: Origin, update: AltSvcUpdate) {
+        match update {
+            AltSvcUpdate::Alternatives(alternatives) if !alternatives.is_empty() => {
+                self.alternatives.insert(origin, alternatives);
+            }
+            AltSvcUpdate::Alternatives(_) | AltSvcUpdate::Clear => self.clear(&origin),
+        }
+    }
+
+    /// Removes all alternatives of `origin`.
+    pub fn clear(&mut self, origin: &Origin) {
+        self.alternatives.remove(origin);
+    }
+
+    /// Removes all alternatives not marked `persist`.
+    ///
+    /// > When alternative services are used to send a client to the most
+    /// > optimal server, a change in network configuration can result in
+    /// > cached values becoming suboptimal. Therefore, clients SHOULD remove
+    /// > from cache all alternative services that lack the "persist" flag
+    /// > with the value "1" when they detect such a change, when information
+    /// > about network state is available.
+    ///
+    /// <https://datatracker.ietf.org/doc/html/rfc7838#section-3.1>
+    pub fn on_network_change(&mut self) {
+        for alternatives in self.alternatives.values_mut() {
+            alternatives.retain(|alt_svc| alt_svc.persist);
+        }
+        self.alternatives
+            .retain(|_, alternatives| !alternatives.is_empty());
+    }
+
+    /// Removes all alternatives expired by `now`.
+    pub fn remove_expired(&mut self, now: Instant) {
+        for alternatives in self.alternatives.values_mut() {
+            alternatives.retain(|alt_svc| !alt_svc.is_expired(now));
+        }
+        self.alternatives
+            .retain(|_, alternatives| !alternatives.is_empty());
+    }
+
+    /// The alternatives of `origin` not expired by `now`, e.g. for
+    /// [`NetworkConfig::alt_svc`](crate::NetworkConfig::alt_svc).
+    pub fn get(&self, origin: &Origin, now: Instant) -> Vec<AltSvc> {
+        self.alternatives
+            .get(origin)
+            .into_iter()
+            .flatten()
+            .filter(|alt_svc| !alt_svc.is_expired(now))
+            .cloned()
+            .collect()
+    }
+
+    /// Snapshot of the cache for persistence.
+    ///
+    /// As [`Instant`]s are meaningless across restarts, expiries are
+    /// converted to wall-clock time relative to `now` and `wall_now`.
+    /// Alternatives whose expiry overflows wall-clock time are left out.
+    pub fn snapshot(&self, now: Instant, wall_now: SystemTime) -> AltSvcCacheSnapshot {
+        let entries = self
+            .alternatives
+            .iter()
+            .flat_map(|(origin, alternatives)| {
+                alternatives
+                    .iter()
+                    .filter(|alt_svc| !alt_svc.is_expired(now))
+                    .filter_map(|alt_svc| {
+                        let expires = match alt_svc.expires {
+                            // Dropped if not representable in wall-clock time.
+                            Some(expires) => {
+                                Some(wall_now.checked_add(expires.duration_since(now))?)
+                            }
+                            None => None,
+                        };
+                        Some(SnapshotEntry {
+                            origin: origin.clone(),
+                            host: alt_svc.host.clone(),
+                            port: alt_svc.port,
+                            protocol: alt_svc.protocol,
+                            expires,
+                            persist: alt_svc.persist,
+                        })
+                    })
+            })
+            .collect();
+        AltSvcCacheSnapshot { entries }
+    }
+
+    /// Restores a cache from a snapshot, dropping alternatives expired by
+    /// `wall_now` or whose expiry overflows [`Instant`].
+    pub fn restore(snapshot: AltSvcCacheSnapshot, now: Instant, wall_now: SystemTime) -> Self {
+        let mut cache = Self::default();
+        for entry in snapshot.entries {
+            let expires = match entry.expires {
+                Some(expires) => match expires.duration_since(wall_now) {
+                    Ok(remaining) => match now.checked_add(remaining) {
+                        Some(expires) => Some(expires),
+                        // Not representable, rather than never expiring.
+                        None => continue,
+                    },
+                    // Expired.
+                    Err(_) => continue,
+                },
+                None => None,
+            };
+            cache
+                .alternatives
+                .entry(entry.origin)
+                .or_default()
+                .push(AltSvc {
+                    host: entry.host,
+                    port: entry.port,
+                    protocol: entry.protocol,
+                    expires,
+                    persist: entry.persist,
+                });
+        }
+        cache
+    }
+}
+
+/// Persistable snapshot of an [`AltSvcCache`], see
+/// [`AltSvcCache::snapshot`]. Serializable with the `serde` feature enabled.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AltSvcCacheSnapshot {
+    entries: Vec<SnapshotEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct SnapshotEntry {
+    origin: Origin,
+    host: Option<String>,
+    port: Option<u16>,
+    protocol: HttpVersion,
+    expires: Option<SystemTime>,
+    persist: bool,
+}
@@ -0,0 +1,78 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::{HttpVersion, Origin};
+
+/// How long an alternative is considered broken after its first failure.
+///
+/// Each further failure without an intermediate success doubles the delay.
+pub const BROKEN_ALTERNATIVE_INITIAL_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Upper bound for the delay an alternative is considered broken.
+pub const BROKEN_ALTERNATIVE_MAX_DELAY: Duration = Duration::from_secs(2 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Broken {
+    /// Number of times marked broken since the last confirmation.
+    count: u32,
+    until: Instant,
+}
+
+/// Registry of alternative protocols that recently failed for an origin,
+/// e.g. HTTP/3 advertised via Alt-Svc or an HTTPS record while UDP is
+/// blocked on the path to the origin.
+///
+/// Modeled after Chromium's broken alternative services. An alternative
+/// marked broken is left out of races to the origin until its delay expires.
+/// The delay starts at [`BROKEN_ALTERNATIVE_INITIAL_DELAY`] and doubles with
+/// each failure, up to [`BROKEN_ALTERNATIVE_MAX_DELAY`], until the
+/// alternative is confirmed working again.
+///
+/// Pass the broken protocols of an origin to a race via
+/// [`NetworkConfig::broken_alternatives`](crate::NetworkConfig::broken_alternatives)
+/// and record the race's
+/// [`HappyEyeballs::broken_alternatives`](crate::HappyEyeballs::broken_alternatives)
+/// afterwards.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BrokenAlternatives {
+    broken: HashMap<(Origin, HttpVersion), Broken>,
+}
+
+impl BrokenAlternatives {
+    /// Marks `protocol` broken for `origin`, backing off exponentially on
+    /// repeated failures.
+    pub fn mark_broken(&mut self, origin: Origin, protocol: HttpVersion, now: Instant) {
+        let broken = self.broken.entry((origin, protocol)).or_insert(Broken {
+            count: 0,
+            until: now,
+        });
+        let delay = BROKEN_ALTERNATIVE_INITIAL_DELAY
+            .checked_mul(2u32.saturating_pow(broken.count))
+            .map_or(BROKEN_ALTERNATIVE_MAX_DELAY, |d| {
+                d.min(BROKEN_ALTERNATIVE_MAX_DELAY)
+            });
+        broken.count = broken.count.saturating_add(1);
+        broken.until = now + delay;
+    }
+
+    /// Marks `protocol` working for `origin`, resetting its backoff.
+    pub fn confirm(&mut self, origin: &Origin, protocol: HttpVersion) {
+        self.broken.remove(&(origin.clone(), protocol));
+    }
+
+    /// Whether `protocol` is considered broken for `origin` at `now`.
+    pub fn is_broken(&self, origin: &Origin, protocol: HttpVersion, now: Instant) -> bool {
+        self.broken
+            .get(&(origin.clone(), protocol))
+            .is_some_and(|b| now < b.until)
+    }
+
+    /// The protocols considered broken for `origin` at `now`.
+    pub fn broken_protocols(&self, origin: &Origin, now: Instant) -> HashSet<HttpVersion> {
+        self.broken
+            .iter()
+            .filter(|((o, _), b)| o == origin && now < b.until)
+            .map(|((_, protocol), _)| *protocol)
+            .collect()
+    }
+}
@@ -0,0 +1,88 @@
+//! Encrypted Client Hello configuration, see
+//! [draft-ietf-tls-esni](https://datatracker.ietf.org/doc/html/draft-ietf-tls-esni).
+
+/// The ECHConfig version supported, see draft-ietf-tls-esni Section 4.
+const ECH_VERSION: u16 = 0xfe0d;
+
+/// Extracts the public name of the first ECHConfig of a supported version
+/// from an ECHConfigList, e.g. as contained in the `ech` SvcParam of an HTTPS
+/// record.
+///
+/// ```text
+/// ECHConfig ECHConfigList<4..2^16-1>;
+///
+/// struct {
+///     uint16 version;
+///     uint16 length;
+///     select (ECHConfig.version) {
+///       case 0xfe0d: ECHConfigContents contents;
+///     }
+/// } ECHConfig;
+///
+/// struct {
+///     HpkeKeyConfig key_config;
+///     uint8 maximum_name_length;
+///     opaque public_name<1..255>;
+///     ECHConfigExtension extensions<0..2^16-1>;
+/// } ECHConfigContents;
+/// ```
+///
+/// Returns [`None`] if the list is malformed or contains no supported
+/// ECHConfig.
+pub(crate) fn public_name(ech_config_list: &[u8]) -> Option<String> {
+    let mut list = Reader(ech_config_list);
+    let mut configs = Reader(list.vec16()?);
+    while !configs.0.is_empty() {
+        let version = configs.u16()?;
+        let contents = configs.vec16()?;
+        if version != ECH_VERSION {
+            continue;
+        }
+
+        let mut contents = Reader(contents);
+        // HpkeKeyConfig: config_id, kem_id, public_key, cipher_suites.
+        contents.u8()?;
+        contents.u16()?;
+        contents.vec16()?;
+        contents.vec16()?;
+        // maximum_name_length
+        contents.u8()?;
+        let public_name = contents.vec8()?;
+        if public_name.is_empty() {
+            return None;
+        }
+        return String::from_utf8(public_name.to_vec()).ok();
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?;
+        self.take(usize::from(len))
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(usize::from(len))
+    }
+}
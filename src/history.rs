@@ -0,0 +1,173 @@
+use std::cmp::Ordering;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::IpPrefix;
+
+/// What a [`HistoryRecord`] applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HistoryKey {
+    /// A single address.
+    Address(IpAddr),
+    /// All addresses within a prefix.
+    Prefix(IpPrefix),
+}
+
+/// Observed outcome of past connection attempts.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HistoryRecord {
+    /// Smoothed round-trip time of successful connection attempts.
+    pub rtt: Option<Duration>,
+    /// Number of successful connection attempts.
+    pub successes: u32,
+    /// Number of failed connection attempts.
+    pub failures: u32,
+}
+
+impl HistoryRecord {
+    /// Never succeeded, but failed at least once.
+    fn failed_only(&self) -> bool {
+        self.successes == 0 && self.failures > 0
+    }
+}
+
+/// History of connection attempts from previous races.
+///
+/// Used to sort endpoints:
+///
+/// > If the client is stateful and has a history of expected round-trip
+/// > times (RTTs) for the routes to access each address, it SHOULD add a
+/// > Destination Address Selection rule between rules 8 and 9 that prefers
+/// > addresses with lower RTTs. If the client keeps track of which addresses
+/// > it used in the past, it SHOULD add another Destination Address Selection
+/// > rule between the RTT rule and rule 9, which prefers used addresses over
+/// > unused ones.
+///
+/// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-5>
+///
+/// The RTTs of the history also serve as a fallback for
+/// [`RttEstimates`](crate::RttEstimates).
+///
+/// [`HappyEyeballs`](crate::HappyEyeballs) records the outcome of each of its
+/// connection attempts. Retrieve the updated history via
+/// [`HappyEyeballs::history`](crate::HappyEyeballs::history) and pass it to
+/// the next race. With the `serde` feature enabled, the history can be
+/// persisted.
+///
+/// At most [`ConnectionHistory::MAX_RECORDS`] records are kept. Once full,
+/// the least recently updated record is dropped.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "RawConnectionHistory"))]
+pub struct ConnectionHistory {
+    /// Ordered from least to most recently updated.
+    records: Vec<(HistoryKey, HistoryRecord)>,
+}
+
+/// The fields of a [`ConnectionHistory`] as persisted, bounded to
+/// [`ConnectionHistory::MAX_RECORDS`] on deserialization.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawConnectionHistory {
+    records: Vec<(HistoryKey, HistoryRecord)>,
+}
+
+#[cfg(feature = "serde")]
+impl From<RawConnectionHistory> for ConnectionHistory {
+    fn from(mut raw: RawConnectionHistory) -> Self {
+        // Keep the most recently updated records.
+        let excess = raw.records.len().saturating_sub(Self::MAX_RECORDS);
+        let mut history = Self::default();
+        for (key, record) in raw.records.drain(excess..) {
+            history.insert(key, record);
+        }
+        history
+    }
+}
+
+impl ConnectionHistory {
+    /// Maximum number of records kept.
+    pub const MAX_RECORDS: usize = 1024;
+
+    /// Sets the record for `key`, replacing any previous record.
+    pub fn insert(&mut self, key: HistoryKey, record: HistoryRecord) {
+        *self.entry(key) = record;
+    }
+
+    /// Records a successful connection attempt with the given round-trip
+    /// time.
+    pub fn record_success(&mut self, key: HistoryKey, rtt: Duration) {
+        let record = self.entry(key);
+        record.successes = record.successes.saturating_add(1);
+        // Smoothed like TCP's SRTT, see RFC 6298.
+        record.rtt = Some(match record.rtt {
+            Some(srtt) => srtt.mul_f64(7.0 / 8.0) + rtt.mul_f64(1.0 / 8.0),
+            None => rtt,
+        });
+    }
+
+    /// Records a failed connection attempt.
+    pub fn record_failure(&mut self, key: HistoryKey) {
+        let record = self.entry(key);
+        record.failures = record.failures.saturating_add(1);
+    }
+
+    /// Returns the most specific record for `ip`, i.e. the record of the
+    /// address itself, or else the record of the longest matching prefix.
+    pub fn get(&self, ip: IpAddr) -> Option<&HistoryRecord> {
+        self.records
+            .iter()
+            .filter_map(|(key, record)| match key {
+                HistoryKey::Address(address) if *address == ip => Some((u16::MAX, record)),
+                HistoryKey::Prefix(prefix) if prefix.contains(ip) => {
+                    Some((u16::from(prefix.prefix_len()), record))
+                }
+                _ => None,
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, record)| record)
+    }
+
+    /// Iterates over all records, e.g. to persist them.
+    pub fn iter(&self) -> impl Iterator<Item = (&HistoryKey, &HistoryRecord)> {
+        self.records.iter().map(|(key, record)| (key, record))
+    }
+
+    /// Orders `a` before `b` if history suggests `a` is the better path.
+    ///
+    /// Addresses that only ever failed go last. Otherwise addresses with a
+    /// lower RTT go first, addresses with a known RTT before addresses
+    /// without.
+    pub(crate) fn cmp(&self, a: IpAddr, b: IpAddr) -> Ordering {
+        let (a, b) = (self.get(a), self.get(b));
+
+        let failed_only = |r: Option<&HistoryRecord>| r.is_some_and(|r| r.failed_only());
+        let order = failed_only(a).cmp(&failed_only(b));
+        if order != Ordering::Equal {
+            return order;
+        }
+
+        match (a.and_then(|r| r.rtt), b.and_then(|r| r.rtt)) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
+    /// Returns the record for `key`, marked as most recently updated.
+    fn entry(&mut self, key: HistoryKey) -> &mut HistoryRecord {
+        let record = match self.records.iter().position(|(k, _)| *k == key) {
+            Some(index) => self.records.remove(index).1,
+            None => HistoryRecord::default(),
+        };
+        if self.records.len() >= Self::MAX_RECORDS {
+            let excess = self.records.len() + 1 - Self::MAX_RECORDS;
+            self.records.drain(..excess);
+        }
+        self.records.push((key, record));
+        &mut self.records.last_mut().expect("just pushed").1
+    }
+}
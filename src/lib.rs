@@ -22,7 +22,7 @@
 //!
 //! // First process outputs from the state machine, e.g. a DNS query to send:
 //! # let mut dns_id: Option<Id> = None;
-//! while let Some(output) = he.process_output(now) {
+//! for output in he.drain_outputs(now) {
 //!     match output {
 //!         Output::SendDnsQuery { id, hostname, record_type } => {
 //!             // Send DNS query.
@@ -35,6 +35,10 @@
 //!     }
 //! }
 //!
+//! // Then wake the state machine at the deadline via `he.handle_timeout(..)`,
+//! // unless an input arrives earlier.
+//! let deadline = he.poll_timeout();
+//!
 //! // Later pass results as input back to the state machine, e.g. a DNS
 //! // response arrives:
 //! # let dns_result = DnsResult::Aaaa(Ok(vec![Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)]));
@@ -44,19 +48,51 @@
 //! For complete example usage, see the tests in [`tests/integration.rs`](tests/integration.rs).
 
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
 use std::time::{Duration, Instant};
 
-use log::trace;
+use log::{debug, trace};
 use thiserror::Error;
 use url::Host;
 
+mod alt_svc;
+pub use alt_svc::{
+    AltSvc, AltSvcFrame, AltSvcParseError, AltSvcUpdate, DEFAULT_ALT_SVC_MAX_AGE,
+    MAX_ALT_SVC_MAX_AGE, parse_alt_svc_header, parse_h2_altsvc_frame, parse_h3_altsvc_frame,
+};
+
+mod alt_svc_cache;
+pub use alt_svc_cache::{AltSvcCache, AltSvcCacheSnapshot};
+
+mod broken_alternatives;
+pub use broken_alternatives::{
+    BROKEN_ALTERNATIVE_INITIAL_DELAY, BROKEN_ALTERNATIVE_MAX_DELAY, BrokenAlternatives,
+};
+
+mod ech;
+
+mod history;
+pub use history::{ConnectionHistory, HistoryKey, HistoryRecord};
+
 mod id;
 pub use id::Id;
 use id::IdGenerator;
 
+mod network_state;
+pub use network_state::{NetworkId, NetworkState, NetworkStateStore};
+
+mod prefix;
+pub use prefix::IpPrefix;
+
+mod rfc6724;
+pub use rfc6724::SourceAddress;
+
+mod rtt;
+pub use rtt::{RttEstimates, RttKey};
+
 /// > The RECOMMENDED value for the Resolution Delay is 50 milliseconds.
 ///
 /// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-4.2>
@@ -68,6 +104,74 @@ pub const RESOLUTION_DELAY: Duration = Duration::from_millis(50);
 /// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-9>
 pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 
+/// > Minimum Connection Attempt Delay (Section 6): The minimum time to wait
+/// > between connection attempts. Recommended to be 100 milliseconds. MUST
+/// > NOT be less than 10 milliseconds.
+///
+/// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-9>
+pub const MIN_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(100);
+
+/// > Maximum Connection Attempt Delay (Section 6): The maximum time to wait
+/// > between connection attempts. Recommended to be 2 seconds.
+///
+/// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-9>
+pub const MAX_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_secs(2);
+
+/// Default for [`Timing::dns_timeout`].
+///
+/// Not specified by the draft.
+pub const DEFAULT_DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default for [`Timing::connection_attempt_timeout`].
+///
+/// Not specified by the draft.
+pub const DEFAULT_CONNECTION_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default for [`Timing::ipv6_blackhole_timeout`].
+///
+/// Not specified by the draft.
+pub const DEFAULT_IPV6_BLACKHOLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// > First Address Family Count (Section 5): The number of addresses
+/// > belonging to the preferred address family (such as IPv6) that should be
+/// > attempted before attempting the next address family. Recommended to be
+/// > 1; 2 may be used to more aggressively favor a particular combination of
+/// > address family and protocol.
+///
+/// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-9>
+pub const FIRST_ADDRESS_FAMILY_COUNT: usize = 1;
+
+/// Default for [`NetworkConfig::quic_demotion_threshold`].
+///
+/// A single failure might well be specific to the address. Two failures
+/// suggest UDP being blocked on the path.
+pub const DEFAULT_QUIC_DEMOTION_THRESHOLD: usize = 2;
+
+/// Default for [`NetworkConfig::max_concurrent_attempts`].
+pub const DEFAULT_MAX_CONCURRENT_ATTEMPTS: usize = 8;
+
+/// Default for [`Limits::max_target_names`].
+pub const DEFAULT_MAX_TARGET_NAMES: usize = 4;
+
+/// Default for [`Limits::max_dns_queries`].
+pub const DEFAULT_MAX_DNS_QUERIES: usize = 16;
+
+/// Default for [`Limits::max_endpoints`].
+pub const DEFAULT_MAX_ENDPOINTS: usize = 32;
+
+/// Default for [`Limits::max_services`].
+pub const DEFAULT_MAX_SERVICES: usize = 16;
+
+/// Default for [`Limits::max_address_hints`].
+pub const DEFAULT_MAX_ADDRESS_HINTS: usize = 8;
+
+/// Lower bound for [`Timing::min_connection_attempt_delay`].
+///
+/// > MUST NOT be less than 10 milliseconds.
+///
+/// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-9>
+const CONNECTION_ATTEMPT_DELAY_FLOOR: Duration = Duration::from_millis(10);
+
 /// Input events to the Happy Eyeballs state machine
 #[derive(Debug, Clone, PartialEq)]
 pub enum Input {
@@ -81,6 +185,10 @@ pub enum Input {
         // TODO: When attempting a connection with ECH, the remote might send a
         // new ECH config to us on failure. That might be carried in this event?
     },
+
+    /// New round-trip time estimate, e.g. from a connection of a concurrent
+    /// race. Replaces any previous estimate for the same key.
+    RttEstimate { key: RttKey, rtt: Duration },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -109,9 +217,7 @@ impl DnsResult {
 
     fn flatten_into_endpoints(
         &self,
-        port: u16,
-        got_a: bool,
-        got_aaaa: bool,
+        target: EndpointTarget<'_>,
         protocols: HashSet<ConnectionAttemptHttpVersions>,
         ech_config: Option<Vec<u8>>,
     ) -> Vec<Endpoint> {
@@ -123,7 +229,7 @@ impl DnsResult {
                 .flat_map(|infos| {
                     infos
                         .iter()
-                        .flat_map(|info| info.flatten_into_endpoints(port, got_a, got_aaaa))
+                        .flat_map(|info| info.flatten_into_endpoints(target))
                 })
                 // TODO: way around allocation?
                 .collect(),
@@ -135,10 +241,8 @@ impl DnsResult {
                     addrs.iter().cloned().flat_map(|ip| {
                         // TODO: way around allocation?
                         let ech_config = ech_config.clone();
-                        protocols.iter().map(move |p| Endpoint {
-                            address: SocketAddr::new(IpAddr::V6(ip), port),
-                            protocol: *p,
-                            ech_config: ech_config.clone(),
+                        protocols.iter().map(move |p| {
+                            Endpoint::new(IpAddr::V6(ip), *p, ech_config.clone(), target)
                         })
                     })
                 })
@@ -152,10 +256,8 @@ impl DnsResult {
                     addrs.iter().cloned().flat_map(|ip| {
                         // TODO: way around allocation?
                         let ech_config = ech_config.clone();
-                        protocols.iter().map(move |p| Endpoint {
-                            address: SocketAddr::new(IpAddr::V4(ip), port),
-                            protocol: *p,
-                            ech_config: ech_config.clone(),
+                        protocols.iter().map(move |p| {
+                            Endpoint::new(IpAddr::V4(ip), *p, ech_config.clone(), target)
                         })
                     })
                 })
@@ -168,6 +270,12 @@ impl DnsResult {
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct TargetName(String);
 
+impl TargetName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 impl From<&str> for TargetName {
     fn from(s: &str) -> Self {
         TargetName(s.to_string())
@@ -197,24 +305,43 @@ pub enum Output {
     },
 
     /// Start a timer
-    Timer {
-        duration: Duration,
-    },
+    Timer { duration: Duration },
 
     /// Attempt to connect to an address
-    AttemptConnection {
-        id: Id,
-        endpoint: Endpoint,
-    },
+    AttemptConnection { id: Id, endpoint: Endpoint },
 
     // TODO: Consider a CancelSendDnsQuery.
-    /// Cancel a connection attempt
-    CancelConnection(SocketAddr),
+    /// Cancel the connection attempt with the given id
+    CancelConnection(Id),
 
     /// Connection attempt succeeded
     Succeeded,
 
-    Failed,
+    /// All connection attempts failed, or none could be made
+    Failed(FailureReason),
+}
+
+/// The result of a finished race, see [`HappyEyeballs::outcome`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// A connection attempt succeeded, see [`Output::Succeeded`].
+    Succeeded,
+    /// The race failed, see [`Output::Failed`].
+    Failed(FailureReason),
+}
+
+/// Why a race failed, see [`Output::Failed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    /// All endpoints were attempted and failed, or no endpoint was resolved.
+    Exhausted,
+    /// The host is only reachable over an address family disabled via
+    /// [`NetworkConfig::ip`], e.g. an IPv6 literal with
+    /// [`IpPreference::Ipv4Only`].
+    AddressFamilyDisabled,
+    /// The [`NetworkConfig::attempt_budget`] was used up before all
+    /// endpoints were attempted.
+    AttemptBudgetExhausted,
 }
 
 impl Output {
@@ -273,40 +400,60 @@ impl Debug for ServiceInfo {
 }
 
 impl ServiceInfo {
-    fn flatten_into_endpoints(&self, port: u16, got_a: bool, got_aaaa: bool) -> Vec<Endpoint> {
+    /// The TargetName, with `.` resolved to `owner`.
+    ///
+    /// > For ServiceMode SVCB RRs, if TargetName has the value ".", then the
+    /// > owner name of this record MUST be used as the effective TargetName.
+    ///
+    /// <https://datatracker.ietf.org/doc/html/rfc9460#section-2.5.2>
+    fn target_name_or<'a>(&'a self, owner: &'a TargetName) -> &'a TargetName {
+        if self.target_name.as_str() == "." {
+            owner
+        } else {
+            &self.target_name
+        }
+    }
+
+    /// Endpoints of the address hints.
+    ///
+    /// > ServiceMode records can contain address hints via ipv6hint and
+    /// > ipv4hint parameters. When these are received, they SHOULD be
+    /// > considered as positive non-empty answers for the purpose of the
+    /// > algorithm when A and AAAA records corresponding to the TargetName
+    /// > are not available yet.
+    ///
+    /// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-4.2.1>
+    ///
+    /// Once available, hints not confirmed by the A and AAAA records are
+    /// attempted last, see [`HappyEyeballs::sorted_endpoints`].
+    fn flatten_into_endpoints(&self, target: EndpointTarget<'_>) -> Vec<Endpoint> {
+        let target = EndpointTarget {
+            target_name: self.target_name_or(target.target_name),
+            provenance: Provenance {
+                source: EndpointSource::AddressHint,
+                service_priority: Some(self.priority),
+            },
+            ..target
+        };
         self.ipv6_hints
             .iter()
             .cloned()
             .map(IpAddr::V6)
             .chain(self.ipv4_hints.iter().cloned().map(IpAddr::V4))
-            // > ServiceMode records can contain address hints via ipv6hint and
-            // > ipv4hint parameters. When these are received, they SHOULD be
-            // > considered as positive non-empty answers for the purpose of the
-            // > algorithm when A and AAAA records corresponding to the TargetName
-            // > are not available yet.
-            //
-            // <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-4.2.1>
-            .filter(|ip| match ip {
-                IpAddr::V6(_) => !got_aaaa,
-                IpAddr::V4(_) => !got_a,
-            })
             .flat_map(|ip| {
                 // TODO: way around allocation?
                 let ech_config = self.ech_config.clone();
                 ConnectionAttemptHttpVersions::from_protocols(&self.alpn_protocols)
                     .into_iter()
-                    .map(move |protocol| Endpoint {
-                        address: SocketAddr::new(ip, port),
-                        // TODO: Only take the overlap with HappyEyeballs::protocols().
-                        protocol,
-                        ech_config: ech_config.clone(),
-                    })
+                    // TODO: Only take the overlap with HappyEyeballs::protocols().
+                    .map(move |protocol| Endpoint::new(ip, protocol, ech_config.clone(), target))
             })
             .collect()
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HttpVersion {
     H3,
     H2,
@@ -327,6 +474,10 @@ pub enum ConnectionAttemptHttpVersions {
 }
 
 impl ConnectionAttemptHttpVersions {
+    fn is_quic(&self) -> bool {
+        matches!(self, ConnectionAttemptHttpVersions::H3)
+    }
+
     /// [`HttpVersion::H2`] and [`HttpVersion::H1`] into [`ConnectionAttemptHttpVersions::H2OrH1`].
     fn from_protocols(protocols: &HashSet<HttpVersion>) -> HashSet<ConnectionAttemptHttpVersions> {
         let mut combinations = HashSet::new();
@@ -350,6 +501,7 @@ enum DnsQuery {
         id: Id,
         target_name: TargetName,
         record_type: DnsRecordType,
+        started: Instant,
     },
     Completed {
         id: Id,
@@ -415,6 +567,16 @@ impl Default for HttpVersions {
     }
 }
 
+impl HttpVersions {
+    fn contains(&self, version: HttpVersion) -> bool {
+        match version {
+            HttpVersion::H3 => self.h3,
+            HttpVersion::H2 => self.h2,
+            HttpVersion::H1 => self.h1,
+        }
+    }
+}
+
 /// IP connectivity and preference mode.
 #[derive(Debug, Clone, PartialEq)]
 pub enum IpPreference {
@@ -428,22 +590,193 @@ pub enum IpPreference {
     Ipv4Only,
 }
 
-/// Alternative service information from previous connections.
+/// Timing parameters of the algorithm.
 ///
-/// See [RFC 7838](https://datatracker.ietf.org/doc/html/rfc7838).
-#[derive(Debug, Clone)]
-pub struct AltSvc {
-    pub host: Option<String>,
-    pub port: Option<u16>,
-    pub protocol: HttpVersion,
+/// Defaults to the values recommended by the draft. See
+/// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-9>.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timing {
+    /// Time to wait for outstanding DNS answers once the first answer arrived.
+    pub resolution_delay: Duration,
+    /// Time to wait between connection attempts in the absence of RTT data.
+    pub connection_attempt_delay: Duration,
+    /// Lower bound of the connection attempt delay. Raised to 10 milliseconds
+    /// if set lower.
+    pub min_connection_attempt_delay: Duration,
+    /// Upper bound of the connection attempt delay.
+    pub max_connection_attempt_delay: Duration,
+    /// Time after which an unanswered DNS query is treated as a negative
+    /// answer.
+    pub dns_timeout: Duration,
+    /// Time after which a connection attempt in progress is treated as
+    /// failed and canceled.
+    pub connection_attempt_timeout: Duration,
+    /// Time after which an IPv6 connection attempt in progress counts towards
+    /// [`NetworkConfig::ipv6_blackhole_threshold`], well before it times out.
+    pub ipv6_blackhole_timeout: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            resolution_delay: RESOLUTION_DELAY,
+            connection_attempt_delay: CONNECTION_ATTEMPT_DELAY,
+            min_connection_attempt_delay: MIN_CONNECTION_ATTEMPT_DELAY,
+            max_connection_attempt_delay: MAX_CONNECTION_ATTEMPT_DELAY,
+            dns_timeout: DEFAULT_DNS_TIMEOUT,
+            connection_attempt_timeout: DEFAULT_CONNECTION_ATTEMPT_TIMEOUT,
+            ipv6_blackhole_timeout: DEFAULT_IPV6_BLACKHOLE_TIMEOUT,
+        }
+    }
+}
+
+impl Timing {
+    /// The configured connection attempt delay, clamped to the configured
+    /// minimum and maximum.
+    fn connection_attempt_delay(&self) -> Duration {
+        self.clamp_connection_attempt_delay(self.connection_attempt_delay)
+    }
+
+    fn clamp_connection_attempt_delay(&self, delay: Duration) -> Duration {
+        let min = self
+            .min_connection_attempt_delay
+            .max(CONNECTION_ATTEMPT_DELAY_FLOOR);
+        let max = self.max_connection_attempt_delay.max(min);
+        delay.clamp(min, max)
+    }
+}
+
+/// Bounds on the work a single race does, e.g. protecting against an HTTPS
+/// answer listing hundreds of services.
+///
+/// When truncating, the services with the highest priority, i.e. the lowest
+/// SvcPriority, are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    /// Number of distinct TargetNames of HTTPS records, other than the
+    /// origin's host, to resolve and use address hints of.
+    pub max_target_names: usize,
+    /// Total number of DNS queries, including the initial queries for the
+    /// origin's host, which are always sent.
+    pub max_dns_queries: usize,
+    /// Total number of candidate endpoints.
+    pub max_endpoints: usize,
+    /// Number of ServiceMode records of an HTTPS answer to consider.
+    pub max_services: usize,
+    /// Number of address hints per address family of a ServiceMode record to
+    /// consider.
+    pub max_address_hints: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_target_names: DEFAULT_MAX_TARGET_NAMES,
+            max_dns_queries: DEFAULT_MAX_DNS_QUERIES,
+            max_endpoints: DEFAULT_MAX_ENDPOINTS,
+            max_services: DEFAULT_MAX_SERVICES,
+            max_address_hints: DEFAULT_MAX_ADDRESS_HINTS,
+        }
+    }
+}
+
+/// Budget of connection attempts per race, see
+/// [`NetworkConfig::attempt_budget`].
+///
+/// Each attempt costs the weight of its address family times the weight of
+/// its protocol. With all weights 1, as by [`AttemptBudget::new`], the
+/// budget is the maximum number of attempts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttemptBudget {
+    /// Total cost of all attempts of a race.
+    pub max: u32,
+    /// Weight of IPv6 attempts.
+    pub ipv6_weight: u32,
+    /// Weight of IPv4 attempts.
+    pub ipv4_weight: u32,
+    /// Weight of QUIC (HTTP/3) attempts.
+    pub quic_weight: u32,
+    /// Weight of TCP (HTTP/2 and HTTP/1.1) attempts.
+    pub tcp_weight: u32,
+}
+
+impl AttemptBudget {
+    /// A budget of at most `max` attempts.
+    pub fn new(max: u32) -> Self {
+        Self {
+            max,
+            ipv6_weight: 1,
+            ipv4_weight: 1,
+            quic_weight: 1,
+            tcp_weight: 1,
+        }
+    }
+
+    fn cost(&self, endpoint: &Endpoint) -> u32 {
+        let family = match AddressFamily::of(endpoint.address.ip()) {
+            AddressFamily::V6 => self.ipv6_weight,
+            AddressFamily::V4 => self.ipv4_weight,
+        };
+        let protocol = if endpoint.protocol.is_quic() {
+            self.quic_weight
+        } else {
+            self.tcp_weight
+        };
+        family.saturating_mul(protocol)
+    }
+}
+
+/// IP address family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressFamily {
+    V6,
+    V4,
+}
+
+impl AddressFamily {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V6(_) => AddressFamily::V6,
+            IpAddr::V4(_) => AddressFamily::V4,
+        }
+    }
+}
+
+/// Policy for racing QUIC (HTTP/3) against TCP (HTTP/2 and HTTP/1.1)
+/// connection attempts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ProtocolRacing {
+    /// Attempt all QUIC endpoints before any TCP endpoint, waiting the
+    /// connection attempt delay between each attempt.
+    #[default]
+    QuicFirst,
+    /// Attempt QUIC to an address, followed by TCP to the same address once
+    /// QUIC had the given head start, before moving on to the next address
+    /// after the connection attempt delay.
+    QuicHeadStart(Duration),
+    /// Attempt QUIC and TCP to the same address at the same time, before
+    /// moving on to the next address after the connection attempt delay.
+    Parallel,
+    /// Attempt all TCP endpoints before any QUIC endpoint, waiting the
+    /// connection attempt delay between each attempt.
+    TcpFirst,
+}
+
+/// Policy for Encrypted Client Hello, see
+/// [draft-ietf-tls-esni](https://datatracker.ietf.org/doc/html/draft-ietf-tls-esni).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EchPolicy {
+    /// Never use ECH, i.e. leave [`Endpoint::ech_config`] empty.
+    Disabled,
+    /// Use ECH where advertised, connecting without ECH otherwise.
+    #[default]
+    Opportunistic,
+    /// Once any service advertised an ECH config, only attempt endpoints
+    /// with an ECH config, never falling back to a cleartext SNI, e.g. as an
+    /// attacker might strip the ECH config of a single service.
+    Required,
 }
 
-// TODO: Allow user to provide alt-svc information from previous connections.
-//
-// TODO: We need to track whether HTTP RR DNS is enabled or disabled. There is a pref for it in Firefox.
-//
-// TODO: We need to track whether ECH is enabled or disabled. There is a pref for it in Firefox.
-//
 // TODO: Should we make HappyEyeballs proxy aware? E.g. should it know that the
 // proxy is resolving the domain? Should it still trigger an HTTP RR lookup to
 // see whether the remote supports HTTP/3? Should it first do MASQUE connect-udp
@@ -468,6 +801,63 @@ pub struct NetworkConfig {
     pub ip: IpPreference,
     /// Alternative services from previous connections
     pub alt_svc: Vec<AltSvc>,
+    /// Resolution and connection attempt delays
+    pub timing: Timing,
+    /// Round-trip time estimates used to derive the connection attempt delay
+    pub rtt_estimates: RttEstimates,
+    /// Connection history from previous races used to sort endpoints
+    pub history: ConnectionHistory,
+    /// Source addresses of the local host used for RFC 6724 destination
+    /// address selection. Destination address selection is skipped when
+    /// empty.
+    pub source_addresses: Vec<SourceAddress>,
+    /// Number of endpoints of the first address family to attempt before
+    /// interleaving with the other address family. Values below 1 are treated
+    /// as 1.
+    pub first_address_family_count: usize,
+    /// How to race QUIC against TCP
+    pub protocol_racing: ProtocolRacing,
+    /// Number of failed QUIC connection attempts, e.g. due to a timeout or
+    /// an ICMP port unreachable, after which the remaining QUIC endpoints of
+    /// the race are attempted after the TCP endpoints. Attempts canceled
+    /// because another attempt succeeded do not count. [`None`] disables the
+    /// demotion.
+    pub quic_demotion_threshold: Option<usize>,
+    /// Number of IPv6 connection attempts that failed or went unanswered for
+    /// [`Timing::ipv6_blackhole_timeout`], while no IPv4 connection attempt
+    /// failed, after which the remaining IPv4 endpoints of the race are
+    /// attempted before the remaining IPv6 endpoints. Defaults to [`None`],
+    /// i.e. no detection.
+    pub ipv6_blackhole_threshold: Option<usize>,
+    /// Findings about the current network from previous races, see
+    /// [`NetworkStateStore::findings`]. IPv6 being broken promotes IPv4
+    /// endpoints, QUIC being blocked demotes QUIC endpoints from the start,
+    /// ECH being stripped requires ECH where advertised.
+    pub known_findings: Findings,
+    /// Protocols currently considered broken for the origin, see
+    /// [`BrokenAlternatives::broken_protocols`]. Left out of the race, unless
+    /// no other protocol remains.
+    pub broken_alternatives: HashSet<HttpVersion>,
+    /// Whether to use Encrypted Client Hello
+    pub ech: EchPolicy,
+    /// Whether to send GREASE ECH to endpoints without an ECH config, see
+    /// [`Endpoint::ech_grease`]. Ignored with [`EchPolicy::Disabled`].
+    pub ech_grease: bool,
+    /// Whether to query HTTPS records, e.g. disabled via a browser pref.
+    /// When disabled, the connection attempt phase doesn't wait for SVCB
+    /// information and the protocols default to HTTP/2 and HTTP/1.1, plus
+    /// any alternative services.
+    pub https_rr: bool,
+    /// Bounds on the number of TargetNames, DNS queries and endpoints
+    pub limits: Limits,
+    /// Number of connection attempts in progress at the same time, beyond
+    /// which no further attempt is started until one of them concludes.
+    /// [`None`] disables the cap.
+    pub max_concurrent_attempts: Option<usize>,
+    /// Budget of connection attempts per race. Once used up, the race fails
+    /// with [`FailureReason::AttemptBudgetExhausted`] instead of attempting
+    /// the remaining endpoints. [`None`] leaves the attempts unbounded.
+    pub attempt_budget: Option<AttemptBudget>,
 }
 
 impl Default for NetworkConfig {
@@ -476,11 +866,45 @@ impl Default for NetworkConfig {
             http_versions: HttpVersions::default(),
             ip: IpPreference::DualStackPreferV6,
             alt_svc: Vec::new(),
+            timing: Timing::default(),
+            rtt_estimates: RttEstimates::default(),
+            history: ConnectionHistory::default(),
+            source_addresses: Vec::new(),
+            first_address_family_count: FIRST_ADDRESS_FAMILY_COUNT,
+            protocol_racing: ProtocolRacing::default(),
+            quic_demotion_threshold: Some(DEFAULT_QUIC_DEMOTION_THRESHOLD),
+            ipv6_blackhole_threshold: None,
+            known_findings: Findings::default(),
+            broken_alternatives: HashSet::new(),
+            ech: EchPolicy::default(),
+            ech_grease: false,
+            https_rr: true,
+            limits: Limits::default(),
+            max_concurrent_attempts: Some(DEFAULT_MAX_CONCURRENT_ATTEMPTS),
+            attempt_budget: None,
         }
     }
 }
 
 impl NetworkConfig {
+    /// Whether connecting via `family` is enabled, see [`IpPreference`].
+    fn allows(&self, family: AddressFamily) -> bool {
+        !matches!(
+            (&self.ip, family),
+            (IpPreference::Ipv6Only, AddressFamily::V4)
+                | (IpPreference::Ipv4Only, AddressFamily::V6)
+        )
+    }
+
+    /// Whether to send DNS queries for `record_type`.
+    fn queries(&self, record_type: DnsRecordType) -> bool {
+        match record_type {
+            DnsRecordType::Https => self.https_rr,
+            DnsRecordType::Aaaa => self.allows(AddressFamily::V6),
+            DnsRecordType::A => self.allows(AddressFamily::V4),
+        }
+    }
+
     fn prefer_v6(&self) -> bool {
         match self.ip {
             IpPreference::DualStackPreferV6 | IpPreference::Ipv6Only => true,
@@ -501,6 +925,8 @@ pub enum ConnectionState {
     InProgress,
     Succeeded,
     Failed,
+    /// Canceled, as another connection attempt succeeded.
+    Canceled,
 }
 
 #[derive(Debug, Clone)]
@@ -512,8 +938,8 @@ pub struct ConnectionAttempt {
 }
 
 impl ConnectionAttempt {
-    fn within_delay(&self, now: Instant) -> bool {
-        now.duration_since(self.started) < CONNECTION_ATTEMPT_DELAY
+    fn within_delay(&self, now: Instant, delay: Duration) -> bool {
+        now.duration_since(self.started) < delay
     }
 }
 
@@ -523,14 +949,141 @@ pub struct Endpoint {
     pub address: SocketAddr,
     pub protocol: ConnectionAttemptHttpVersions,
     pub ech_config: Option<Vec<u8>>,
+    /// Host of the origin, to be used for TLS SNI and certificate
+    /// validation.
+    ///
+    /// Differs from the host the address was resolved for when connecting to
+    /// an alternative service hosted elsewhere, see [`AltSvc::host`].
+    pub origin_host: String,
+    /// The name the address was resolved for, i.e. the origin's host, the
+    /// TargetName of an HTTPS record or the host of an alternative service.
+    pub target_name: TargetName,
+    /// Public name of the ECH configuration, to be used for SNI of the
+    /// ClientHelloOuter, see [`Endpoint::ech_config`].
+    pub ech_public_name: Option<String>,
+    /// Whether to send a GREASE ECH extension in the absence of an ECH
+    /// config, see [`NetworkConfig::ech_grease`].
+    ///
+    /// <https://datatracker.ietf.org/doc/html/draft-ietf-tls-esni#section-6.2>
+    pub ech_grease: bool,
+    /// Where the endpoint came from, e.g. for logging and telemetry.
+    pub provenance: Provenance,
+}
+
+/// Where an [`Endpoint`] came from.
+///
+/// The name the address was resolved for is [`Endpoint::target_name`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Provenance {
+    pub source: EndpointSource,
+    /// Priority of the HTTPS record ServiceMode the address came from, i.e.
+    /// carrying the address hint or with the TargetName the address was
+    /// resolved for. [`None`] for the origin's own A and AAAA answers, which
+    /// are queried regardless of any HTTPS record.
+    pub service_priority: Option<u16>,
+}
+
+/// The record an [`Endpoint`]'s address came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointSource {
+    /// An `ipv6hint` or `ipv4hint` of an HTTPS record.
+    AddressHint,
+    /// An A or AAAA answer.
+    DnsAnswer,
+    /// An A or AAAA answer for the host of an alternative service, see
+    /// [`AltSvc::host`].
+    AltSvc,
+    /// The host itself is an IP address.
+    IpLiteral,
+}
+
+/// Where endpoints resolved from a DNS response connect to.
+#[derive(Debug, Clone, Copy)]
+struct EndpointTarget<'a> {
+    port: u16,
+    origin_host: &'a str,
+    target_name: &'a TargetName,
+    provenance: Provenance,
 }
 
 impl Endpoint {
-    fn sort_with_config(&self, other: &Endpoint, network_config: &NetworkConfig) -> Ordering {
+    fn new(
+        ip: IpAddr,
+        protocol: ConnectionAttemptHttpVersions,
+        ech_config: Option<Vec<u8>>,
+        target: EndpointTarget<'_>,
+    ) -> Self {
+        Endpoint {
+            address: SocketAddr::new(ip, target.port),
+            protocol,
+            ech_public_name: ech_config.as_deref().and_then(ech::public_name),
+            ech_config,
+            ech_grease: false,
+            origin_host: target.origin_host.to_string(),
+            target_name: target.target_name.clone(),
+            provenance: target.provenance,
+        }
+    }
+
+    /// Whether both endpoints connect to the same address with the same
+    /// protocol, regardless of where they came from.
+    fn same_attempt(&self, other: &Endpoint) -> bool {
+        self.attempt_key() == other.attempt_key()
+    }
+
+    /// What identifies a connection attempt, see [`Endpoint::same_attempt`].
+    fn attempt_key(&self) -> (SocketAddr, ConnectionAttemptHttpVersions) {
+        (self.address, self.protocol)
+    }
+
+    /// Orders endpoints by protocol, then address, taking into account what
+    /// was learned about the network during the race.
+    fn sort_with_config(
+        &self,
+        other: &Endpoint,
+        network_config: &NetworkConfig,
+        findings: &Findings,
+    ) -> Ordering {
+        if findings.ipv6_blackhole {
+            let order = self.address.is_ipv6().cmp(&other.address.is_ipv6());
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+
         if self.protocol != other.protocol {
+            if findings.quic_demoted || network_config.protocol_racing == ProtocolRacing::TcpFirst {
+                let order = self.protocol.is_quic().cmp(&other.protocol.is_quic());
+                if order != Ordering::Equal {
+                    return order;
+                }
+            }
             return self.protocol.cmp(&other.protocol);
         }
 
+        let (a, b) = (self.address.ip(), other.address.ip());
+        let sources = &network_config.source_addresses;
+
+        if !sources.is_empty() {
+            let order = rfc6724::cmp_destinations(a, b, sources, !network_config.prefer_v6());
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+
+        // History rules go between RFC 6724 rules 8 and 9.
+        let order = network_config.history.cmp(a, b);
+        if order != Ordering::Equal {
+            return order;
+        }
+
+        if !sources.is_empty() {
+            let order = rfc6724::cmp_longest_matching_prefix(a, b, sources);
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+
         let order = self
             .address
             .ip()
@@ -544,6 +1097,149 @@ impl Endpoint {
     }
 }
 
+/// Removes the endpoints that would lead to the same connection attempt as an
+/// earlier one, see [`Endpoint::same_attempt`].
+///
+/// The same address might e.g. be both an address hint and a DNS answer. Keeps
+/// the first, unless it is a hint and the other is not.
+fn deduplicate(endpoints: Vec<Endpoint>) -> Vec<Endpoint> {
+    let mut indices = HashMap::with_capacity(endpoints.len());
+    let mut deduplicated: Vec<Endpoint> = Vec::with_capacity(endpoints.len());
+    for endpoint in endpoints {
+        match indices.get(&endpoint.attempt_key()) {
+            Some(&i) => {
+                let e: &mut Endpoint = &mut deduplicated[i];
+                if e.provenance.source == EndpointSource::AddressHint
+                    && endpoint.provenance.source != EndpointSource::AddressHint
+                {
+                    *e = endpoint;
+                }
+            }
+            None => {
+                indices.insert(endpoint.attempt_key(), deduplicated.len());
+                deduplicated.push(endpoint);
+            }
+        }
+    }
+    deduplicated
+}
+
+/// Interleaves address families within each protocol group of the sorted
+/// `endpoints`.
+///
+/// Starts with `first_address_family_count` endpoints of the address family
+/// of the first endpoint of the group, then alternates between the other and
+/// the first address family. Once one address family is exhausted, the
+/// remaining endpoints of the other family follow in order.
+///
+/// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-5>
+fn interleave_address_families(
+    endpoints: Vec<Endpoint>,
+    first_address_family_count: usize,
+) -> Vec<Endpoint> {
+    let mut interleaved = Vec::with_capacity(endpoints.len());
+
+    for group in endpoints.chunk_by(|a, b| a.protocol == b.protocol) {
+        let first_family = AddressFamily::of(group[0].address.ip());
+        let (mut first, mut other): (VecDeque<_>, VecDeque<_>) = group
+            .iter()
+            .cloned()
+            .partition(|e| AddressFamily::of(e.address.ip()) == first_family);
+
+        for _ in 0..first_address_family_count.max(1) {
+            interleaved.extend(first.pop_front());
+        }
+
+        let mut take_other = true;
+        while !first.is_empty() || !other.is_empty() {
+            let (next, fallback) = if take_other {
+                (&mut other, &mut first)
+            } else {
+                (&mut first, &mut other)
+            };
+            interleaved.extend(next.pop_front().or_else(|| fallback.pop_front()));
+            take_other = !take_other;
+        }
+    }
+
+    interleaved
+}
+
+/// Moves the TCP endpoints of each address right behind the QUIC endpoint of
+/// the same address.
+///
+/// Expects the QUIC endpoints to precede the TCP endpoints, as sorted by
+/// [`Endpoint::sort_with_config`]. TCP endpoints without a QUIC endpoint to
+/// the same address keep their order at the end.
+fn pair_quic_with_tcp(endpoints: Vec<Endpoint>) -> Vec<Endpoint> {
+    let (quic, mut tcp): (Vec<_>, Vec<_>) =
+        endpoints.into_iter().partition(|e| e.protocol.is_quic());
+
+    let mut paired = Vec::with_capacity(quic.len() + tcp.len());
+    for endpoint in quic {
+        let ip = endpoint.address.ip();
+        paired.push(endpoint);
+        let (same_address, rest): (Vec<_>, Vec<_>) =
+            tcp.into_iter().partition(|e| e.address.ip() == ip);
+        paired.extend(same_address);
+        tcp = rest;
+    }
+    paired.extend(tcp);
+
+    paired
+}
+
+/// The origin a race connects to, see [RFC
+/// 6454](https://datatracker.ietf.org/doc/html/rfc6454).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Origin {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+}
+
+/// What a race learned about the current network.
+///
+/// A race sorts endpoints by [`NetworkConfig::known_findings`] combined with
+/// what it learned itself. Once set, a finding holds for the remainder of the
+/// race. Retrieve the learned findings via [`HappyEyeballs::findings`] and
+/// record them in a [`NetworkStateStore`] for future races on the same
+/// network.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Findings {
+    /// IPv6 connection attempts failed while IPv4 endpoints were waiting,
+    /// see [`NetworkConfig::ipv6_blackhole_threshold`].
+    pub ipv6_blackhole: bool,
+    /// QUIC connection attempts failed repeatedly, see
+    /// [`NetworkConfig::quic_demotion_threshold`].
+    ///
+    /// A heuristic based on the attempts to a single origin, thus possibly
+    /// specific to its servers rather than a fact about the network. Consider
+    /// confirming it, e.g. across origins, before recording it in a
+    /// [`NetworkStateStore`].
+    pub quic_demoted: bool,
+    /// Some services advertised an ECH config while others did not,
+    /// suggesting the ECH configs of the latter were stripped on the path.
+    ///
+    /// Once known, see [`NetworkConfig::known_findings`],
+    /// [`EchPolicy::Opportunistic`] leaves out endpoints without an ECH config
+    /// like [`EchPolicy::Required`].
+    pub ech_stripped: bool,
+}
+
+impl Findings {
+    /// The facts of either `self` or `other`.
+    fn union(&self, other: &Findings) -> Findings {
+        Findings {
+            ipv6_blackhole: self.ipv6_blackhole || other.ipv6_blackhole,
+            quic_demoted: self.quic_demoted || other.quic_demoted,
+            ech_stripped: self.ech_stripped || other.ech_stripped,
+        }
+    }
+}
+
 /// Happy Eyeballs v3 state machine
 pub struct HappyEyeballs {
     id_generator: IdGenerator,
@@ -551,6 +1247,28 @@ pub struct HappyEyeballs {
     connection_attempts: Vec<ConnectionAttempt>,
     /// Network configuration
     network_config: NetworkConfig,
+    /// [`NetworkConfig::history`] updated with the outcome of this race.
+    ///
+    /// Kept separate, so that the order of endpoints does not change based on
+    /// the race's own attempts.
+    history: ConnectionHistory,
+    /// Learned during this race, as opposed to
+    /// [`NetworkConfig::known_findings`].
+    findings: Findings,
+    /// When the race started, i.e. the first call to
+    /// [`HappyEyeballs::process_output`].
+    started: Option<Instant>,
+    /// The latest time passed in, see [`HappyEyeballs::poll_timeout`].
+    last_now: Option<Instant>,
+    /// Connection attempts timed out, yet to be canceled via
+    /// [`Output::CancelConnection`].
+    timed_out: Vec<Id>,
+    /// Set once [`Output::Succeeded`] or [`Output::Failed`] was returned.
+    outcome: Option<Outcome>,
+    /// Cache of [`HappyEyeballs::sorted_endpoints`], reset whenever a DNS
+    /// response arrives, a connection attempt starts or something is learned
+    /// about the network.
+    sorted_endpoints: OnceLock<Vec<Endpoint>>,
     host: Host,
     port: u16,
 }
@@ -618,6 +1336,13 @@ impl HappyEyeballs {
         };
         let s = Self {
             id_generator: IdGenerator::new(),
+            history: network_config.history.clone(),
+            findings: Findings::default(),
+            started: None,
+            last_now: None,
+            timed_out: Vec::new(),
+            outcome: None,
+            sorted_endpoints: OnceLock::new(),
             network_config,
             dns_queries: Vec::new(),
             connection_attempts: Vec::new(),
@@ -628,6 +1353,58 @@ impl HappyEyeballs {
         Ok(s)
     }
 
+    /// The connection history, updated with the outcome of this race's
+    /// connection attempts.
+    ///
+    /// Pass it to the next race via [`NetworkConfig::history`].
+    pub fn history(&self) -> &ConnectionHistory {
+        &self.history
+    }
+
+    /// The origin this race connects to.
+    pub fn origin(&self) -> Origin {
+        Origin {
+            scheme: "https".to_string(),
+            host: self.host.to_string(),
+            port: self.port,
+        }
+    }
+
+    /// Alternative protocols that failed while another protocol succeeded,
+    /// i.e. HTTP/3 when all QUIC connection attempts failed but a TCP
+    /// connection attempt succeeded.
+    ///
+    /// Mark them broken via [`BrokenAlternatives::mark_broken`]. When instead
+    /// a connection attempt using an alternative succeeded, confirm it via
+    /// [`BrokenAlternatives::confirm`].
+    pub fn broken_alternatives(&self) -> HashSet<HttpVersion> {
+        let mut quic_attempts = self
+            .connection_attempts
+            .iter()
+            .filter(|a| a.endpoint.protocol.is_quic())
+            .peekable();
+        let quic_failed = quic_attempts.peek().is_some()
+            && quic_attempts.all(|a| a.state == ConnectionState::Failed);
+        let tcp_succeeded = self
+            .connection_attempts
+            .iter()
+            .any(|a| !a.endpoint.protocol.is_quic() && a.state == ConnectionState::Succeeded);
+
+        let mut broken = HashSet::new();
+        if quic_failed && tcp_succeeded {
+            broken.insert(HttpVersion::H3);
+        }
+        broken
+    }
+
+    /// What this race learned about the current network so far.
+    ///
+    /// Excludes [`NetworkConfig::known_findings`] unless the race learned them
+    /// anew, so that recording them does not renew stale facts.
+    pub fn findings(&self) -> &Findings {
+        &self.findings
+    }
+
     /// Process an input event
     ///
     /// Updates internal state based on the input.
@@ -635,73 +1412,263 @@ impl HappyEyeballs {
     /// After calling this, call [`HappyEyeballs::process_output`] to get any pending outputs.
     pub fn process_input(&mut self, input: Input, now: Instant) {
         trace!("target={} input={:?}", self.host, input);
+        self.last_now = Some(now);
 
         match input {
             Input::DnsResult { id, result } => {
                 self.on_dns_response(id, result, now);
             }
             Input::ConnectionResult { id, result } => {
-                self.on_connection_result(id, result);
+                self.on_connection_result(id, result, now);
+            }
+            Input::RttEstimate { key, rtt } => {
+                self.network_config.rtt_estimates.insert(key, rtt);
             }
         }
     }
 
-    // TODO: Does this ever return None given the timeouts?
     /// Generate output based on current state
     ///
     /// Call this to advance the state machine and get any pending outputs.
     ///
     /// The caller must call [`HappyEyeballs::process_output`] repeatedly
-    /// until it returns [`None`] or [`Output::Timer`].
+    /// until it returns [`None`] or [`Output::Timer`], or use
+    /// [`HappyEyeballs::drain_outputs`].
+    ///
+    /// [`Output::Succeeded`] and [`Output::Failed`] are returned once, after
+    /// which the race is finished and [`None`] is returned, see
+    /// [`HappyEyeballs::outcome`].
+    ///
+    /// [`Output::Timer`] covers the resolution and connection attempt delays
+    /// only. Use [`HappyEyeballs::poll_timeout`] instead to also cover the
+    /// DNS and connection attempt timeouts.
     pub fn process_output(&mut self, now: Instant) -> Option<Output> {
+        if self.is_finished() {
+            return None;
+        }
+
         let output = self.process_output_inner(now);
         trace!("target={} process_output: {:?}", self.host, output);
+        match output {
+            Some(Output::Succeeded) => self.outcome = Some(Outcome::Succeeded),
+            Some(Output::Failed(reason)) => self.outcome = Some(Outcome::Failed(reason)),
+            _ => {}
+        }
         output
     }
 
-    fn process_output_inner(&mut self, now: Instant) -> Option<Output> {
-        // Check if we have any successful connection that requires canceling other attempts
-        let output = self.cancel_remaining_attempts();
-        if output.is_some() {
-            return output;
-        }
-
-        // TODO: Move below self.connection_attempt()?
-        // Send DNS queries.
-        let output = self.send_dns_request();
-        if output.is_some() {
-            return output;
-        }
+    /// Drains all outputs ready at `now`.
+    ///
+    /// The iterator ends once the race is idle, i.e. waiting for an input or
+    /// a timeout, or finished. [`Output::Timer`] is not yielded, use
+    /// [`HappyEyeballs::poll_timeout`] instead.
+    ///
+    /// ```
+    /// # use std::time::Instant;
+    /// # use happy_eyeballs::{HappyEyeballs, Output};
+    /// let mut he = HappyEyeballs::new("example.com", 443).unwrap();
+    /// let now = Instant::now();
+    /// for output in he.drain_outputs(now) {
+    ///     assert!(matches!(output, Output::SendDnsQuery { .. }));
+    /// }
+    /// assert!(he.poll_timeout().is_some());
+    /// ```
+    pub fn drain_outputs(&mut self, now: Instant) -> impl Iterator<Item = Output> + '_ {
+        std::iter::from_fn(move || match self.process_output(now)? {
+            Output::Timer { .. } => None,
+            output => Some(output),
+        })
+        .fuse()
+    }
 
-        // Attempt connections.
-        let output = self.connection_attempt(now);
-        if output.is_some() {
-            return output;
-        }
+    /// Whether the race finished, i.e. [`Output::Succeeded`] or
+    /// [`Output::Failed`] was returned.
+    pub fn is_finished(&self) -> bool {
+        self.outcome.is_some()
+    }
 
-        let output = self.send_dns_request_for_target_name();
-        if output.is_some() {
-            return output;
-        }
+    /// The result of the race, once finished.
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.outcome
+    }
 
-        let output = self.delay(now);
-        if output.is_some() {
-            return output;
+    /// The next point in time the race needs to be woken up at via
+    /// [`HappyEyeballs::handle_timeout`], if any.
+    ///
+    /// Covers the resolution delay, the connection attempt delay, the DNS
+    /// timeout and the connection attempt timeout, see [`Timing`]. Thus an
+    /// event loop needs a single timer per race, to be reset after each call
+    /// to [`HappyEyeballs::process_input`], [`HappyEyeballs::process_output`]
+    /// and [`HappyEyeballs::handle_timeout`].
+    ///
+    /// Modeled after `quinn_proto::Connection::poll_timeout`.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let now = self.last_now?;
+        if self.is_finished() || self.has_successful_connection() {
+            return None;
         }
 
-        if !self.has_successful_connection()
-            && !self.has_pending_queries()
-            && !self.has_pending_connections()
+        let timing = &self.network_config.timing;
+        let dns_timeouts = self.dns_queries.iter().filter_map(|q| match q {
+            DnsQuery::InProgress { started, .. } => Some(*started + timing.dns_timeout),
+            DnsQuery::Completed { .. } => None,
+        });
+        let attempt_timeouts = self
+            .connection_attempts
+            .iter()
+            .filter(|a| a.state == ConnectionState::InProgress)
+            .map(|a| a.started + timing.connection_attempt_timeout);
+
+        self.connection_attempt_deadline()
+            .into_iter()
+            .chain(self.resolution_delay_deadline())
+            .chain(dns_timeouts)
+            .chain(attempt_timeouts)
+            .filter(|deadline| *deadline > now)
+            .min()
+    }
+
+    /// Handles the expiry of the deadline returned by
+    /// [`HappyEyeballs::poll_timeout`].
+    ///
+    /// Call [`HappyEyeballs::process_output`] afterwards.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.last_now = Some(now);
+
+        let timing = self.network_config.timing.clone();
+        let dns_timeouts = self
+            .dns_queries
+            .iter()
+            .filter_map(|q| match q {
+                DnsQuery::InProgress {
+                    id,
+                    record_type,
+                    started,
+                    ..
+                } if now.duration_since(*started) >= timing.dns_timeout => {
+                    Some((*id, *record_type))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for (id, record_type) in dns_timeouts {
+            debug!("target={} DNS query {id:?} timed out", self.host);
+            let response = match record_type {
+                DnsRecordType::Https => DnsResult::Https(Err(())),
+                DnsRecordType::Aaaa => DnsResult::Aaaa(Err(())),
+                DnsRecordType::A => DnsResult::A(Err(())),
+            };
+            self.on_dns_response(id, response, now);
+        }
+
+        let attempt_timeouts = self
+            .connection_attempts
+            .iter()
+            .filter(|a| {
+                a.state == ConnectionState::InProgress
+                    && now.duration_since(a.started) >= timing.connection_attempt_timeout
+            })
+            .map(|a| a.id)
+            .collect::<Vec<_>>();
+        for id in attempt_timeouts {
+            debug!("target={} connection attempt {id:?} timed out", self.host);
+            self.on_connection_result(id, Err("timed out".to_string()), now);
+            self.timed_out.push(id);
+        }
+    }
+
+    fn process_output_inner(&mut self, now: Instant) -> Option<Output> {
+        if self.started.is_none() {
+            self.start(now);
+        }
+        self.handle_timeout(now);
+
+        // Cancel attempts that timed out.
+        if let Some(id) = self.timed_out.pop() {
+            return Some(Output::CancelConnection(id));
+        }
+
+        // Check if we have any successful connection that requires canceling other attempts
+        let output = self.cancel_remaining_attempts();
+        if output.is_some() {
+            return output;
+        }
+
+        // TODO: Move below self.connection_attempt()?
+        // Send DNS queries.
+        let output = self.send_dns_request(now);
+        if output.is_some() {
+            return output;
+        }
+
+        let output = self.send_dns_request_for_alt_svc(now);
+        if output.is_some() {
+            return output;
+        }
+
+        self.detect_ipv6_blackhole(now);
+
+        // Attempt connections.
+        let output = self.connection_attempt(now);
+        if output.is_some() {
+            return output;
+        }
+
+        let output = self.send_dns_request_for_target_name(now);
+        if output.is_some() {
+            return output;
+        }
+
+        let output = self.delay(now);
+        if output.is_some() {
+            return output;
+        }
+
+        // Once the attempt budget is used up, further DNS answers are of no
+        // use.
+        if !self.has_successful_connection()
+            && (!self.has_pending_queries() || self.attempt_budget_exhausted())
+            && !self.has_pending_connections()
         {
-            return Some(Output::Failed);
+            return Some(Output::Failed(self.failure_reason()));
         }
 
-        // TODO: Instead of returning None, how about happy-eyeballs also owns
-        // the dns and connection attempt timeout, thus returning either that
-        // timeout, or Output::Failed here.
         None
     }
 
+    fn failure_reason(&self) -> FailureReason {
+        let disabled = |ip: IpAddr| !self.network_config.allows(AddressFamily::of(ip));
+        let only_disabled = match self.host {
+            Host::Ipv4(ipv4_addr) => disabled(IpAddr::V4(ipv4_addr)),
+            Host::Ipv6(ipv6_addr) => disabled(IpAddr::V6(ipv6_addr)),
+            Host::Domain(_) => {
+                self.connection_attempts.is_empty()
+                    && self
+                        .resolved_endpoints()
+                        .iter()
+                        .any(|e| disabled(e.address.ip()))
+            }
+        };
+        if only_disabled {
+            return FailureReason::AddressFamilyDisabled;
+        }
+        if self.network_config.attempt_budget.is_some()
+            && (self.has_pending_queries()
+                || self.sorted_endpoints().iter().any(|e| !self.attempted(e)))
+        {
+            return FailureReason::AttemptBudgetExhausted;
+        }
+        FailureReason::Exhausted
+    }
+
+    fn start(&mut self, now: Instant) {
+        self.started = Some(now);
+        self.network_config
+            .alt_svc
+            .retain(|alt_svc| !alt_svc.is_expired(now));
+        self.endpoints_changed();
+    }
+
     // TODO: Rename to delay?
     fn delay(&self, now: Instant) -> Option<Output> {
         // If we have a successful connection, no connection attempt delay
@@ -710,26 +1677,43 @@ impl HappyEyeballs {
             return None;
         }
 
-        if let Some(connection_attempt_delay) = self
-            .connection_attempts
+        // No attempt can start before one in progress concludes, which is
+        // signaled via `Input::ConnectionResult`, after which the caller
+        // calls `process_output` anyway. A timer would only
+        // lead to a blocked attempt.
+        if self.at_max_concurrent_attempts() {
+            return None;
+        }
+
+        let deadline = self
+            .connection_attempt_deadline()
+            .filter(|deadline| *deadline > now)
+            .or_else(|| {
+                self.resolution_delay_deadline()
+                    .filter(|deadline| *deadline > now)
+            })?;
+        Some(Output::Timer {
+            duration: deadline - now,
+        })
+    }
+
+    /// When the connection attempt delay of the attempts in progress passes.
+    fn connection_attempt_deadline(&self) -> Option<Instant> {
+        if self.at_max_concurrent_attempts() {
+            return None;
+        }
+
+        let next = self.next_endpoint_to_attempt();
+        self.connection_attempts
             .iter()
             .filter(|a| a.state == ConnectionState::InProgress)
-            .map(|a| &a.started)
+            .map(|a| a.started + self.connection_attempt_delay(&a.endpoint, next.as_ref()))
             .max()
-            .and_then(|started| {
-                let elapsed = now.duration_since(*started);
-                if elapsed < CONNECTION_ATTEMPT_DELAY {
-                    Some(CONNECTION_ATTEMPT_DELAY - elapsed)
-                } else {
-                    None
-                }
-            })
-        {
-            return Some(Output::Timer {
-                duration: connection_attempt_delay,
-            });
-        }
+    }
 
+    /// When the resolution delay passes, if any DNS query is still in
+    /// progress.
+    fn resolution_delay_deadline(&self) -> Option<Instant> {
         // If we have no in-progress DNS queries, no resolution delay needed.
         if !self
             .dns_queries
@@ -746,22 +1730,14 @@ impl HappyEyeballs {
                     completed,
                     // TODO: Currently considers all queries. Should we only consider A and AAAA?
                     ..
-                } => Some(completed),
+                } => Some(*completed),
                 _ => None,
             })
             .min()
-            .and_then(|completed| {
-                let elapsed = now.duration_since(*completed);
-                if elapsed < RESOLUTION_DELAY {
-                    Some(RESOLUTION_DELAY - elapsed)
-                } else {
-                    None
-                }
-            })
-            .map(|duration| Output::Timer { duration })
+            .map(|completed| completed + self.network_config.timing.resolution_delay)
     }
 
-    fn send_dns_request(&mut self) -> Option<Output> {
+    fn send_dns_request(&mut self, now: Instant) -> Option<Output> {
         let target_name: TargetName = match &self.host {
             Host::Ipv4(_) | Host::Ipv6(_) => {
                 // No DNS queries needed for IP hosts.
@@ -771,8 +1747,10 @@ impl HappyEyeballs {
         }
         .into();
 
-        // TODO: What if v4 or v6 is disabled? Don't send the query.
         for record_type in [DnsRecordType::Https, DnsRecordType::Aaaa, DnsRecordType::A] {
+            if !self.network_config.queries(record_type) {
+                continue;
+            }
             if !self
                 .dns_queries
                 .iter()
@@ -783,6 +1761,47 @@ impl HappyEyeballs {
                     id,
                     target_name: target_name.clone(),
                     record_type,
+                    started: now,
+                });
+                return Some(Output::SendDnsQuery {
+                    id,
+                    hostname: target_name,
+                    record_type,
+                });
+            }
+        }
+
+        None
+    }
+
+    /// Resolves the hosts of alternative services hosted elsewhere, see
+    /// [`AltSvc::host`].
+    fn send_dns_request_for_alt_svc(&mut self, now: Instant) -> Option<Output> {
+        if !matches!(self.host, Host::Domain(_)) {
+            // Alternative services are only used for domain hosts.
+            return None;
+        }
+
+        for target_name in self.alt_svc_hosts() {
+            if self.dns_queries.len() >= self.network_config.limits.max_dns_queries {
+                return None;
+            }
+            for record_type in [DnsRecordType::Aaaa, DnsRecordType::A] {
+                if !self.network_config.queries(record_type)
+                    || self
+                        .dns_queries
+                        .iter()
+                        .any(|q| *q.target_name() == target_name && q.record_type() == record_type)
+                {
+                    continue;
+                }
+
+                let id = self.id_generator.next_id();
+                self.dns_queries.push(DnsQuery::InProgress {
+                    id,
+                    target_name: target_name.clone(),
+                    record_type,
+                    started: now,
                 });
                 return Some(Output::SendDnsQuery {
                     id,
@@ -795,31 +1814,21 @@ impl HappyEyeballs {
         None
     }
 
-    // TODO: Limit number of target names.
     /// > Note that clients are still required to issue A and AAAA queries
     /// > for those TargetNames if they haven't yet received those records.
     ///
     /// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-4.2.1>
-    fn send_dns_request_for_target_name(&mut self) -> Option<Output> {
-        // Check if we have HTTPS response with ServiceInfo
-        let target_names = self
-            .dns_queries
-            .iter()
-            .filter_map(|q| match q {
-                DnsQuery::Completed {
-                    response: DnsResult::Https(Ok(service_infos)),
-                    ..
-                } => Some(service_infos.iter().map(|i| &i.target_name)),
-                _ => None,
-            })
-            .flatten();
-
-        for target_name in target_names {
+    fn send_dns_request_for_target_name(&mut self, now: Instant) -> Option<Output> {
+        for target_name in self.target_names() {
+            if self.dns_queries.len() >= self.network_config.limits.max_dns_queries {
+                return None;
+            }
             for record_type in [DnsRecordType::Aaaa, DnsRecordType::A] {
-                if self
-                    .dns_queries
-                    .iter()
-                    .any(|q| q.target_name() == target_name && q.record_type() == record_type)
+                if !self.network_config.queries(record_type)
+                    || self
+                        .dns_queries
+                        .iter()
+                        .any(|q| *q.target_name() == target_name && q.record_type() == record_type)
                 {
                     continue;
                 }
@@ -831,6 +1840,7 @@ impl HappyEyeballs {
                     id,
                     target_name: target_name.clone(),
                     record_type,
+                    started: now,
                 });
                 return Some(Output::SendDnsQuery {
                     id,
@@ -857,12 +1867,29 @@ impl HappyEyeballs {
             }
         };
 
+        let response = match response {
+            DnsResult::Https(Ok(mut infos)) => {
+                // Keep the services with the highest priority.
+                let limits = &self.network_config.limits;
+                infos.sort_by_key(|info| info.priority);
+                infos.truncate(limits.max_services);
+                for info in &mut infos {
+                    info.ipv6_hints.truncate(limits.max_address_hints);
+                    info.ipv4_hints.truncate(limits.max_address_hints);
+                }
+                DnsResult::Https(Ok(infos))
+            }
+            response => response,
+        };
         *query = DnsQuery::Completed {
             id,
             target_name,
             completed: now,
             response,
         };
+        self.endpoints_changed();
+
+        self.detect_ech_stripping();
     }
 
     /// > When one connection attempt succeeds (generally when the TCP handshake
@@ -871,7 +1898,7 @@ impl HappyEyeballs {
     /// > connection SHOULD be ignored.
     ///
     /// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-6>
-    fn on_connection_result(&mut self, id: Id, result: Result<(), String>) {
+    fn on_connection_result(&mut self, id: Id, result: Result<(), String>, now: Instant) {
         let Some(attempt) = self.connection_attempts.iter_mut().find(|a| a.id == id) else {
             debug_assert!(false, "got connection result for unknown id {id:?}");
             return;
@@ -883,15 +1910,20 @@ impl HappyEyeballs {
             "got connection result but attempt is not in progress: {attempt:?}"
         );
 
+        let history_key = HistoryKey::Address(attempt.endpoint.address.ip());
         match result {
             Ok(()) => {
                 // Mark this connection as succeeded
                 attempt.state = ConnectionState::Succeeded;
+                self.history
+                    .record_success(history_key, now.duration_since(attempt.started));
                 // Cancellations will be issued by cancel_remaining_attempts()
             }
             Err(_error) => {
                 // Mark connection as failed
                 attempt.state = ConnectionState::Failed;
+                self.history.record_failure(history_key);
+                self.detect_quic_demotion();
 
                 // The state machine will naturally attempt the next connection
                 // when process() is called again with None input
@@ -912,9 +1944,8 @@ impl HappyEyeballs {
             .iter_mut()
             .find(|a| a.state == ConnectionState::InProgress)
         {
-            let address = attempt.endpoint.address;
-            attempt.state = ConnectionState::Failed;
-            return Some(Output::CancelConnection(address));
+            attempt.state = ConnectionState::Canceled;
+            return Some(Output::CancelConnection(attempt.id));
         }
 
         // All connections have been canceled, return Succeeded
@@ -943,16 +1974,34 @@ impl HappyEyeballs {
         if !move_on {
             return None;
         }
+        // Whether endpoints without ECH may be attempted is unknown until
+        // the HTTPS records arrived.
+        if self.network_config.ech == EchPolicy::Required
+            && self.dns_queries.iter().any(|q| {
+                matches!(q, DnsQuery::InProgress { .. }) && q.record_type() == DnsRecordType::Https
+            })
+        {
+            return None;
+        }
+
+        if self.at_max_concurrent_attempts() {
+            return None;
+        }
 
+        let endpoint = self.next_endpoint_to_attempt()?;
         if self
             .connection_attempts
             .iter()
             .filter(|a| a.state == ConnectionState::InProgress)
-            .any(|a| a.within_delay(now))
+            .any(|a| {
+                a.within_delay(
+                    now,
+                    self.connection_attempt_delay(&a.endpoint, Some(&endpoint)),
+                )
+            })
         {
             return None;
         }
-        let endpoint = self.next_endpoint_to_attempt()?;
         let id = self.id_generator.next_id();
 
         self.connection_attempts.push(ConnectionAttempt {
@@ -961,98 +2010,487 @@ impl HappyEyeballs {
             started: now,
             state: ConnectionState::InProgress,
         });
+        self.endpoints_changed();
 
         Some(Output::AttemptConnection { id, endpoint })
     }
 
-    fn next_endpoint_to_attempt(&self) -> Option<Endpoint> {
-        match self.host {
-            Host::Ipv4(ipv4_addr) => {
-                let protocols = self.connection_attempt_protocols();
-                return Some(Endpoint {
-                    address: SocketAddr::new(IpAddr::V4(ipv4_addr), self.port),
-                    protocol: *protocols.iter().next()?,
-                    ech_config: None,
-                });
+    /// The time to wait after starting a connection attempt to `attempted`
+    /// before starting the connection attempt to `next`.
+    ///
+    /// With [`ProtocolRacing::QuicHeadStart`] and [`ProtocolRacing::Parallel`],
+    /// a TCP attempt following a QUIC attempt to the same address waits for
+    /// the head start only, or not at all.
+    ///
+    /// > If the client has historical RTT data gathered from other
+    /// > connections to the same host or prefix, it can use this information
+    /// > to influence its delay.
+    ///
+    /// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-6>
+    ///
+    /// Waits for the estimated round-trip time of the endpoint's address, if
+    /// known, and clamps it to the configured minimum and maximum. Falls back
+    /// to the RTT recorded in the connection history.
+    fn connection_attempt_delay(&self, attempted: &Endpoint, next: Option<&Endpoint>) -> Duration {
+        if let Some(next) = next
+            && attempted.protocol.is_quic()
+            && !next.protocol.is_quic()
+            && attempted.address.ip() == next.address.ip()
+        {
+            match self.network_config.protocol_racing {
+                ProtocolRacing::QuicHeadStart(head_start) => return head_start,
+                ProtocolRacing::Parallel => return Duration::ZERO,
+                ProtocolRacing::QuicFirst | ProtocolRacing::TcpFirst => {}
             }
-            Host::Ipv6(ipv6_addr) => {
-                let protocols = self.connection_attempt_protocols();
-                return Some(Endpoint {
-                    address: SocketAddr::new(IpAddr::V6(ipv6_addr), self.port),
-                    protocol: *protocols.iter().next()?,
-                    ech_config: None,
-                });
+        }
+
+        let timing = &self.network_config.timing;
+        let ip = attempted.address.ip();
+        match self
+            .network_config
+            .rtt_estimates
+            .get(ip)
+            .or_else(|| self.network_config.history.get(ip).and_then(|r| r.rtt))
+        {
+            Some(rtt) => timing.clamp_connection_attempt_delay(rtt),
+            None => timing.connection_attempt_delay(),
+        }
+    }
+
+    fn next_endpoint_to_attempt(&self) -> Option<Endpoint> {
+        let ip = match self.host {
+            Host::Ipv4(ipv4_addr) => Some(IpAddr::V4(ipv4_addr)),
+            Host::Ipv6(ipv6_addr) => Some(IpAddr::V6(ipv6_addr)),
+            Host::Domain(_) => None,
+        };
+        if let Some(ip) = ip {
+            if !self.network_config.allows(AddressFamily::of(ip)) {
+                return None;
             }
-            Host::Domain(_) => {}
+            let origin_host = self.origin_host();
+            let protocols = self.connection_attempt_protocols();
+            let endpoint = Endpoint::new(
+                ip,
+                *protocols.iter().next()?,
+                None,
+                EndpointTarget {
+                    port: self.port,
+                    origin_host: &origin_host,
+                    target_name: &origin_host.as_str().into(),
+                    provenance: Provenance {
+                        source: EndpointSource::IpLiteral,
+                        service_priority: None,
+                    },
+                },
+            );
+            return (!self.attempted(&endpoint) && self.affordable(&endpoint)).then_some(endpoint);
+        }
+
+        // Endpoints exceeding the remaining attempt budget are skipped, as a
+        // cheaper one might still fit.
+        self.sorted_endpoints()
+            .iter()
+            .find(|endpoint| !self.attempted(endpoint) && self.affordable(endpoint))
+            .cloned()
+    }
+
+    /// Whether attempting `endpoint` fits the remaining
+    /// [`NetworkConfig::attempt_budget`].
+    fn affordable(&self, endpoint: &Endpoint) -> bool {
+        self.remaining_attempt_budget()
+            .is_none_or(|remaining| remaining >= self.attempt_cost(endpoint))
+    }
+
+    /// Whether no attempt fits the remaining
+    /// [`NetworkConfig::attempt_budget`] anymore.
+    fn attempt_budget_exhausted(&self) -> bool {
+        let (Some(budget), Some(remaining)) = (
+            &self.network_config.attempt_budget,
+            self.remaining_attempt_budget(),
+        ) else {
+            return false;
+        };
+        let cheapest = budget
+            .ipv6_weight
+            .min(budget.ipv4_weight)
+            .saturating_mul(budget.quic_weight.min(budget.tcp_weight));
+        remaining < cheapest
+    }
+
+    fn remaining_attempt_budget(&self) -> Option<u32> {
+        let budget = self.network_config.attempt_budget.as_ref()?;
+        let spent = self
+            .connection_attempts
+            .iter()
+            .map(|a| self.attempt_cost(&a.endpoint))
+            .fold(0u32, u32::saturating_add);
+        Some(budget.max.saturating_sub(spent))
+    }
+
+    fn attempt_cost(&self, endpoint: &Endpoint) -> u32 {
+        self.network_config
+            .attempt_budget
+            .as_ref()
+            .map_or(0, |budget| budget.cost(endpoint))
+    }
+
+    /// All endpoints resolved so far, in the order to attempt them.
+    ///
+    /// Includes the endpoints already attempted, so that the interleaving of
+    /// address families is stable across calls.
+    ///
+    /// Computed once until [`HappyEyeballs::endpoints_changed`].
+    fn sorted_endpoints(&self) -> &[Endpoint] {
+        self.sorted_endpoints
+            .get_or_init(|| self.compute_sorted_endpoints())
+    }
+
+    /// Resets the cache of [`HappyEyeballs::sorted_endpoints`].
+    fn endpoints_changed(&mut self) {
+        self.sorted_endpoints.take();
+    }
+
+    fn compute_sorted_endpoints(&self) -> Vec<Endpoint> {
+        let mut endpoints = self.resolved_endpoints();
+        endpoints.retain(|e| {
+            self.network_config
+                .allows(AddressFamily::of(e.address.ip()))
+        });
+        let mut endpoints = self.apply_ech_policy(endpoints);
+        let max_endpoints = self.network_config.limits.max_endpoints;
+        if endpoints.len() > max_endpoints {
+            endpoints = self.truncate_endpoints(endpoints, max_endpoints);
+        }
+        let mut endpoints = deduplicate(endpoints);
+        let findings = self.network_config.known_findings.union(&self.findings);
+        endpoints.sort_by(|a, b| a.sort_with_config(b, &self.network_config, &findings));
+        let mut endpoints =
+            interleave_address_families(endpoints, self.network_config.first_address_family_count);
+        if !findings.quic_demoted
+            && matches!(
+                self.network_config.protocol_racing,
+                ProtocolRacing::QuicHeadStart(_) | ProtocolRacing::Parallel
+            )
+        {
+            endpoints = pair_quic_with_tcp(endpoints);
         }
+        // Address hints stand in for the A and AAAA records of their
+        // TargetName only until those arrive. Hints the records did not
+        // confirm are attempted after the authoritative addresses.
+        endpoints.sort_by_key(|e| {
+            e.provenance.source == EndpointSource::AddressHint
+                && self.got_address_records(&e.target_name, e.address.is_ipv6())
+        });
+        endpoints
+    }
 
-        let got_a = self.got_dns_a_response();
-        let got_aaaa = self.got_dns_aaaa_response();
+    /// All endpoints resolved so far, unsorted, of any address family,
+    /// possibly more than once, see [`deduplicate`].
+    fn resolved_endpoints(&self) -> Vec<Endpoint> {
+        let origin_host = self.origin_host();
+        let alt_svc_hosts = self.alt_svc_hosts();
         let mut endpoints = self
             .dns_queries
             .iter()
-            .filter_map(|q| q.get_response())
-            .flat_map(|r| {
+            .filter(|q| !alt_svc_hosts.contains(q.target_name()))
+            .filter_map(|q| Some((q.target_name(), q.get_response()?)))
+            .flat_map(|(target_name, r)| {
                 r.flatten_into_endpoints(
-                    self.port,
-                    got_a,
-                    got_aaaa,
+                    EndpointTarget {
+                        port: self.port,
+                        origin_host: &origin_host,
+                        target_name,
+                        provenance: Provenance {
+                            source: EndpointSource::DnsAnswer,
+                            service_priority: self.service_priority(target_name),
+                        },
+                    },
                     self.connection_attempt_protocols(),
-                    self.ech_config(),
+                    self.ech_config(target_name),
                 )
             })
-            .filter(|endpoint| {
-                !self
-                    .connection_attempts
-                    .iter()
-                    .any(|attempt| attempt.endpoint == *endpoint)
+            .chain(self.alt_svc_endpoints())
+            .collect::<Vec<_>>();
+        // Like their TargetNames, the address hints of services beyond
+        // `Limits::max_target_names` are ignored.
+        let target_names = self.target_names();
+        endpoints.retain(|e| {
+            e.provenance.source != EndpointSource::AddressHint
+                || e.target_name.as_str() == origin_host
+                || target_names.contains(&e.target_name)
+        });
+        endpoints
+    }
+
+    /// The TargetNames of the HTTPS records, other than the origin's host,
+    /// by SvcPriority and bounded by [`Limits::max_target_names`].
+    fn target_names(&self) -> Vec<TargetName> {
+        let origin_host: TargetName = self.origin_host().as_str().into();
+        let mut services = self
+            .dns_queries
+            .iter()
+            .filter_map(|q| match q {
+                DnsQuery::Completed {
+                    response: DnsResult::Https(Ok(infos)),
+                    ..
+                } => Some(
+                    infos
+                        .iter()
+                        .map(|info| (info.priority, info.target_name_or(q.target_name()))),
+                ),
+                _ => None,
             })
+            .flatten()
+            .filter(|(_, target_name)| **target_name != origin_host)
             .collect::<Vec<_>>();
-        endpoints.sort_by(|a, b| a.sort_with_config(b, &self.network_config));
-        endpoints.into_iter().next()
+        services.sort_by_key(|(priority, _)| *priority);
+
+        let mut target_names: Vec<TargetName> = Vec::new();
+        for (_, target_name) in services {
+            if target_names.len() == self.network_config.limits.max_target_names {
+                break;
+            }
+            if !target_names.contains(target_name) {
+                target_names.push(target_name.clone());
+            }
+        }
+        target_names
     }
 
-    fn got_dns_aaaa_response(&self) -> bool {
+    /// Endpoints of the alternative services hosted elsewhere, see
+    /// [`AltSvc::is_elsewhere`].
+    ///
+    /// Uses the port and protocol of the alternative service, while carrying
+    /// the origin's host.
+    fn alt_svc_endpoints(&self) -> Vec<Endpoint> {
+        let origin_host = self.origin_host();
+        let mut endpoints = Vec::new();
+
+        for alt_svc in &self.network_config.alt_svc {
+            if !alt_svc.is_elsewhere(&origin_host, self.port)
+                || !self.network_config.http_versions.contains(alt_svc.protocol)
+                || self
+                    .network_config
+                    .broken_alternatives
+                    .contains(&alt_svc.protocol)
+            {
+                continue;
+            }
+
+            let target_name: TargetName = alt_svc.host.as_deref().unwrap_or(&origin_host).into();
+            let port = alt_svc.port.unwrap_or(self.port);
+            let protocols =
+                ConnectionAttemptHttpVersions::from_protocols(&HashSet::from([alt_svc.protocol]));
+            endpoints.extend(
+                self.dns_queries
+                    .iter()
+                    .filter(|q| *q.target_name() == target_name)
+                    .filter_map(|q| q.get_response())
+                    .filter(|r| matches!(r.record_type(), DnsRecordType::Aaaa | DnsRecordType::A))
+                    .flat_map(|r| {
+                        r.flatten_into_endpoints(
+                            EndpointTarget {
+                                port,
+                                origin_host: &origin_host,
+                                target_name: &target_name,
+                                provenance: Provenance {
+                                    source: EndpointSource::AltSvc,
+                                    service_priority: None,
+                                },
+                            },
+                            protocols.clone(),
+                            None,
+                        )
+                    }),
+            );
+        }
+
+        endpoints
+    }
+
+    /// Hosts of alternative services, other than the origin's host.
+    fn alt_svc_hosts(&self) -> HashSet<TargetName> {
+        let origin_host = self.origin_host();
+        self.network_config
+            .alt_svc
+            .iter()
+            .filter_map(|alt_svc| alt_svc.host.as_deref())
+            .filter(|host| *host != origin_host)
+            .map(TargetName::from)
+            .collect()
+    }
+
+    fn origin_host(&self) -> String {
+        match &self.host {
+            Host::Domain(domain) => domain.clone(),
+            Host::Ipv4(ip) => ip.to_string(),
+            Host::Ipv6(ip) => ip.to_string(),
+        }
+    }
+
+    /// Bounds `endpoints` to `max_endpoints`, see [`Limits::max_endpoints`].
+    ///
+    /// Endpoints already attempted are kept and count against the limit, so
+    /// that the candidates stay stable across calls. Of the others, the
+    /// origin's own answers and the services with the highest priority are
+    /// kept, alternating between address families within each priority.
+    ///
+    /// Duplicates of kept endpoints don't count against the limit, leaving
+    /// the choice between them to [`deduplicate`].
+    fn truncate_endpoints(&self, endpoints: Vec<Endpoint>, max_endpoints: usize) -> Vec<Endpoint> {
+        let (mut kept, mut candidates): (Vec<_>, Vec<_>) =
+            endpoints.into_iter().partition(|e| self.attempted(e));
+        candidates.sort_by_key(|e| e.provenance.service_priority.unwrap_or(0));
+
+        // Rank within the endpoints of the same priority and address family.
+        let mut counts: HashMap<(u16, bool), usize> = HashMap::new();
+        let mut ranked: Vec<(u16, usize, Endpoint)> = Vec::with_capacity(candidates.len());
+        for endpoint in candidates {
+            let priority = endpoint.provenance.service_priority.unwrap_or(0);
+            let count = counts
+                .entry((priority, endpoint.address.is_ipv6()))
+                .or_default();
+            ranked.push((priority, *count, endpoint));
+            *count += 1;
+        }
+        ranked.sort_by_key(|(priority, rank, _)| (*priority, *rank));
+
+        let mut keys: HashSet<_> = kept.iter().map(Endpoint::attempt_key).collect();
+        for (_, _, endpoint) in ranked {
+            if keys.len() >= max_endpoints && !keys.contains(&endpoint.attempt_key()) {
+                continue;
+            }
+            keys.insert(endpoint.attempt_key());
+            kept.push(endpoint);
+        }
+        kept
+    }
+
+    fn attempted(&self, endpoint: &Endpoint) -> bool {
+        self.connection_attempts
+            .iter()
+            .any(|attempt| attempt.endpoint.same_attempt(endpoint))
+    }
+
+    /// Priority of the HTTPS record ServiceMode with the given TargetName,
+    /// unless that's the origin's host, see [`Provenance::service_priority`].
+    fn service_priority(&self, target_name: &TargetName) -> Option<u16> {
+        if target_name.as_str() == self.origin_host() {
+            return None;
+        }
+
         self.dns_queries
             .iter()
-            .filter(|q| {
-                *q.target_name()
-                    == match &self.host {
-                        Host::Domain(d) => d.as_str().into(),
-                        Host::Ipv4(_ipv4_addr) => todo!(),
-                        Host::Ipv6(_ipv6_addr) => todo!(),
-                    }
+            .filter_map(|q| match q.get_response() {
+                Some(DnsResult::Https(Ok(infos))) => Some(infos),
+                _ => None,
             })
-            .any(|q| {
-                matches!(
-                    q,
-                    DnsQuery::Completed {
-                        response: DnsResult::Aaaa(Ok(addrs)),
-                        ..
-                    } if !addrs.is_empty()
-                )
+            .flatten()
+            .filter(|info| info.target_name == *target_name)
+            .map(|info| info.priority)
+            .min()
+    }
+
+    /// Whether enough QUIC connection attempts failed during this race to
+    /// suspect UDP being blocked on the current network, in which case the
+    /// remaining QUIC endpoints are attempted after the TCP endpoints.
+    ///
+    /// Only [`ConnectionState::Failed`] attempts count, including timed out
+    /// ones, not [`ConnectionState::Canceled`] ones.
+    ///
+    /// See [`NetworkConfig::quic_demotion_threshold`].
+    fn detect_quic_demotion(&mut self) {
+        let Some(threshold) = self.network_config.quic_demotion_threshold else {
+            return;
+        };
+
+        if self
+            .connection_attempts
+            .iter()
+            .filter(|a| a.endpoint.protocol.is_quic() && a.state == ConnectionState::Failed)
+            .count()
+            >= threshold.max(1)
+        {
+            self.findings.quic_demoted = true;
+            self.endpoints_changed();
+        }
+    }
+
+    /// Whether enough IPv6 connection attempts failed or went unanswered for
+    /// [`Timing::ipv6_blackhole_timeout`] while IPv4 endpoints are still
+    /// waiting or in progress, and no IPv4 connection attempt failed,
+    /// suggesting IPv6 is broken on the current path. In that case the
+    /// remaining IPv4 endpoints are attempted before the remaining IPv6
+    /// endpoints.
+    ///
+    /// As the race ends with the first successful connection attempt, the
+    /// counted IPv6 attempts are consecutive.
+    ///
+    /// See [`NetworkConfig::ipv6_blackhole_threshold`].
+    fn detect_ipv6_blackhole(&mut self, now: Instant) {
+        if self.findings.ipv6_blackhole {
+            return;
+        }
+        let Some(threshold) = self.network_config.ipv6_blackhole_threshold else {
+            return;
+        };
+        let timeout = self.network_config.timing.ipv6_blackhole_timeout;
+
+        let failed = self
+            .connection_attempts
+            .iter()
+            .filter(|a| a.endpoint.address.is_ipv6())
+            .filter(|a| match a.state {
+                ConnectionState::Failed => true,
+                ConnectionState::InProgress => !a.within_delay(now, timeout),
+                ConnectionState::Succeeded | ConnectionState::Canceled => false,
             })
+            .count();
+        if failed < threshold.max(1) {
+            return;
+        }
+
+        // IPv4 being just as unresponsive points at the server or the
+        // network as a whole, not at IPv6.
+        if self
+            .connection_attempts
+            .iter()
+            .any(|a| a.endpoint.address.is_ipv4() && a.state == ConnectionState::Failed)
+        {
+            return;
+        }
+
+        let ipv4_in_progress = self
+            .connection_attempts
+            .iter()
+            .any(|a| a.endpoint.address.is_ipv4() && a.state == ConnectionState::InProgress);
+        if ipv4_in_progress
+            || self
+                .sorted_endpoints()
+                .iter()
+                .any(|e| e.address.is_ipv4() && !self.attempted(e))
+        {
+            self.findings.ipv6_blackhole = true;
+            self.endpoints_changed();
+        }
     }
 
-    fn got_dns_a_response(&self) -> bool {
+    /// Whether a non-empty AAAA (`ipv6`) or A response for `target_name`
+    /// arrived.
+    fn got_address_records(&self, target_name: &TargetName, ipv6: bool) -> bool {
         self.dns_queries
             .iter()
-            .filter(|q| {
-                *q.target_name()
-                    == match &self.host {
-                        Host::Domain(d) => d.as_str().into(),
-                        Host::Ipv4(_ipv4_addr) => todo!(),
-                        Host::Ipv6(_ipv6_addr) => todo!(),
-                    }
-            })
-            .any(|q| {
-                matches!(
-                    q,
-                    DnsQuery::Completed {
-                        response: DnsResult::A(Ok(addrs)),
-                        ..
-                    } if !addrs.is_empty()
-                )
+            .filter(|q| q.target_name() == target_name)
+            .any(|q| match q {
+                DnsQuery::Completed {
+                    response: DnsResult::Aaaa(Ok(addrs)),
+                    ..
+                } => ipv6 && !addrs.is_empty(),
+                DnsQuery::Completed {
+                    response: DnsResult::A(Ok(addrs)),
+                    ..
+                } => !ipv6 && !addrs.is_empty(),
+                _ => false,
             })
     }
 
@@ -1068,6 +2506,20 @@ impl HappyEyeballs {
             .any(|q| matches!(q, DnsQuery::InProgress { .. }))
     }
 
+    /// Whether [`NetworkConfig::max_concurrent_attempts`] attempts are in
+    /// progress.
+    fn at_max_concurrent_attempts(&self) -> bool {
+        self.network_config
+            .max_concurrent_attempts
+            .is_some_and(|max| {
+                self.connection_attempts
+                    .iter()
+                    .filter(|a| a.state == ConnectionState::InProgress)
+                    .count()
+                    >= max
+            })
+    }
+
     fn has_pending_connections(&self) -> bool {
         self.connection_attempts
             .iter()
@@ -1101,13 +2553,13 @@ impl HappyEyeballs {
             protocols.insert(HttpVersion::H1);
         }
 
-        // Add protocols from alt-svc
+        // Add protocols from alt-svc hosted at the origin. Alternatives
+        // hosted elsewhere have their own endpoints, see alt_svc_endpoints().
+        let origin_host = self.origin_host();
         for alt_svc in &self.network_config.alt_svc {
-            debug_assert!(
-                alt_svc.host.is_none() && alt_svc.port.is_none(),
-                "alt-svc with custom host/port not yet supported"
-            );
-            protocols.insert(alt_svc.protocol);
+            if !alt_svc.is_elsewhere(&origin_host, self.port) {
+                protocols.insert(alt_svc.protocol);
+            }
         }
 
         if !self.network_config.http_versions.h3 {
@@ -1120,33 +2572,96 @@ impl HappyEyeballs {
             protocols.remove(&HttpVersion::H1);
         }
 
+        let working = protocols
+            .difference(&self.network_config.broken_alternatives)
+            .copied()
+            .collect::<HashSet<_>>();
+        if !working.is_empty() {
+            protocols = working;
+        }
+
         ConnectionAttemptHttpVersions::from_protocols(&protocols)
     }
 
-    /// Get the ECH config from HTTPS DNS records for the current host.
-    fn ech_config(&self) -> Option<Vec<u8>> {
-        let target_name: TargetName = match &self.host {
-            Host::Ipv4(_) | Host::Ipv6(_) => {
-                return None;
+    /// Strips, requires or greases ECH according to
+    /// [`NetworkConfig::ech`] and [`NetworkConfig::ech_grease`].
+    fn apply_ech_policy(&self, mut endpoints: Vec<Endpoint>) -> Vec<Endpoint> {
+        match self.network_config.ech {
+            EchPolicy::Disabled => {
+                for endpoint in &mut endpoints {
+                    endpoint.ech_config = None;
+                    endpoint.ech_public_name = None;
+                }
+                return endpoints;
+            }
+            EchPolicy::Opportunistic if !self.network_config.known_findings.ech_stripped => {}
+            EchPolicy::Opportunistic | EchPolicy::Required => {
+                if self.ech_advertised() {
+                    endpoints.retain(|e| e.ech_config.is_some());
+                }
             }
-            Host::Domain(domain) => domain.as_str(),
         }
-        .into();
+        if self.network_config.ech_grease {
+            for endpoint in &mut endpoints {
+                endpoint.ech_grease = endpoint.ech_config.is_none();
+            }
+        }
+        endpoints
+    }
 
+    /// Whether some services advertised an ECH config while others did not.
+    ///
+    /// See [`Findings::ech_stripped`].
+    fn detect_ech_stripping(&mut self) {
+        if self.findings.ech_stripped || !self.ech_advertised() {
+            return;
+        }
+        self.findings.ech_stripped = self.dns_queries.iter().any(|q| {
+            matches!(
+                q,
+                DnsQuery::Completed {
+                    response: DnsResult::Https(Ok(infos)),
+                    ..
+                } if infos.iter().any(|info| info.ech_config.is_none())
+            )
+        });
+    }
+
+    /// Whether any service advertised an ECH config.
+    fn ech_advertised(&self) -> bool {
+        self.dns_queries.iter().any(|q| {
+            matches!(
+                q,
+                DnsQuery::Completed {
+                    response: DnsResult::Https(Ok(infos)),
+                    ..
+                } if infos.iter().any(|info| info.ech_config.is_some())
+            )
+        })
+    }
+
+    /// The ECH config of the service with TargetName `target_name`, if any.
+    ///
+    /// If multiple services share the TargetName, the one with the lowest
+    /// SvcPriority wins. Addresses of a service without ECH get none.
+    fn ech_config(&self, target_name: &TargetName) -> Option<Vec<u8>> {
         self.dns_queries
             .iter()
             .filter_map(|q| match q {
                 DnsQuery::Completed {
                     response: DnsResult::Https(Ok(infos)),
                     ..
-                    // TODO: What about other target names?
-                } if *q.target_name() == target_name => {
-                    infos.iter().find_map(|info| info.ech_config.clone())
-                }
+                } => Some(
+                    infos
+                        .iter()
+                        .filter(|info| info.target_name_or(q.target_name()) == target_name),
+                ),
                 _ => None,
             })
-            // TODO: What if there are multiple?
-            .next()
+            .flatten()
+            .min_by_key(|info| info.priority)?
+            .ech_config
+            .clone()
     }
 
     /// Whether to move on to the connection attempt phase based on the received
@@ -1166,9 +2681,11 @@ impl HappyEyeballs {
             DnsQuery::Completed { response, .. } => match response {
                 DnsResult::Aaaa(Ok(addrs)) => !addrs.is_empty(),
                 DnsResult::A(Ok(addrs)) => !addrs.is_empty(),
-                DnsResult::Https(Ok(infos)) => infos
-                    .iter()
-                    .any(|i| !i.ipv4_hints.is_empty() || !i.ipv6_hints.is_empty()),
+                DnsResult::Https(Ok(infos)) => infos.iter().any(|i| {
+                    (!i.ipv4_hints.is_empty() && self.network_config.allows(AddressFamily::V4))
+                        || (!i.ipv6_hints.is_empty()
+                            && self.network_config.allows(AddressFamily::V6))
+                }),
 
                 _ => false,
             },
@@ -1181,9 +2698,14 @@ impl HappyEyeballs {
         // > for the preferred address family that was queried AND
         //
         // <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-4.2>
+        //
+        // Answers for hosts of alternative services don't count, as the
+        // origin's preferred address family might still be pending.
+        let alt_svc_hosts = self.alt_svc_hosts();
         if !self
             .dns_queries
             .iter()
+            .filter(|q| !alt_svc_hosts.contains(q.target_name()))
             .filter(|q| matches!(q, DnsQuery::Completed { .. }))
             .any(|q| q.record_type() == self.network_config.preferred_dns_record_type())
         {
@@ -1193,12 +2715,15 @@ impl HappyEyeballs {
         // > SVCB/HTTPS service information has been received (or has received a negative response)
         //
         // <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-4.2>
-        if !self
-            .dns_queries
-            .iter()
-            .filter(|q| q.target_name().0 == hostname)
-            .filter(|q| matches!(q, DnsQuery::Completed { .. }))
-            .any(|q| q.record_type() == DnsRecordType::Https)
+        //
+        // Only if queried, see `NetworkConfig::https_rr`.
+        if self.network_config.https_rr
+            && !self
+                .dns_queries
+                .iter()
+                .filter(|q| q.target_name().0 == hostname)
+                .filter(|q| matches!(q, DnsQuery::Completed { .. }))
+                .any(|q| q.record_type() == DnsRecordType::Https)
         {
             return false;
         }
@@ -1231,6 +2756,8 @@ impl HappyEyeballs {
                 DnsQuery::InProgress { .. } => None,
                 DnsQuery::Completed { completed, .. } => Some(completed),
             })
-            .any(|completed| now.duration_since(*completed) >= RESOLUTION_DELAY)
+            .any(|completed| {
+                now.duration_since(*completed) >= self.network_config.timing.resolution_delay
+            })
     }
 }
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use crate::Findings;
+
+/// Caller supplied identifier of a network, e.g. derived from the Wi-Fi SSID
+/// or the cellular carrier.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NetworkId(String);
+
+impl From<String> for NetworkId {
+    fn from(id: String) -> Self {
+        Self(id)
+    }
+}
+
+impl From<&str> for NetworkId {
+    fn from(id: &str) -> Self {
+        Self(id.to_string())
+    }
+}
+
+/// Facts learned about a single network, each with its expiry.
+///
+/// Wall-clock time is used, so that the state stays meaningful across
+/// restarts.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NetworkState {
+    /// IPv6 is broken on the network, see [`Findings::ipv6_blackhole`].
+    pub ipv6_broken: Option<SystemTime>,
+    /// QUIC is blocked on the network, see [`Findings::quic_demoted`], a
+    /// heuristic based on the attempts to a single origin.
+    pub quic_blocked: Option<SystemTime>,
+    /// A middlebox on the network strips ECH, see [`Findings::ech_stripped`].
+    pub ech_stripped: Option<SystemTime>,
+}
+
+impl NetworkState {
+    /// The facts that did not expire by `now`.
+    pub fn findings(&self, now: SystemTime) -> Findings {
+        let valid = |expires: Option<SystemTime>| expires.is_some_and(|e| e > now);
+        Findings {
+            ipv6_blackhole: valid(self.ipv6_broken),
+            quic_demoted: valid(self.quic_blocked),
+            ech_stripped: valid(self.ech_stripped),
+        }
+    }
+
+    /// Records the facts of `findings`, valid until `expires`.
+    ///
+    /// Facts not part of `findings` are left untouched, i.e. a race not
+    /// running into an IPv6 blackhole does not clear a previous one.
+    pub fn record(&mut self, findings: &Findings, expires: SystemTime) {
+        for (found, fact) in [
+            (findings.ipv6_blackhole, &mut self.ipv6_broken),
+            (findings.quic_demoted, &mut self.quic_blocked),
+            (findings.ech_stripped, &mut self.ech_stripped),
+        ] {
+            if found {
+                *fact = Some(expires);
+            }
+        }
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.findings(now) == Findings::default()
+    }
+}
+
+/// Connectivity state learned across races, per network.
+///
+/// Pass the findings for the current network to a race via
+/// [`NetworkConfig::known_findings`](crate::NetworkConfig::known_findings)
+/// and record the race's [`HappyEyeballs::findings`](crate::HappyEyeballs::findings)
+/// afterwards. With the `serde` feature enabled, the store can be persisted.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NetworkStateStore {
+    networks: HashMap<NetworkId, NetworkState>,
+}
+
+impl NetworkStateStore {
+    /// The state of `network`, if any.
+    pub fn get(&self, network: &NetworkId) -> Option<&NetworkState> {
+        self.networks.get(network)
+    }
+
+    /// The state of `network`, inserting an empty state if none exists yet,
+    /// e.g. to record facts learned outside of a race.
+    pub fn get_mut(&mut self, network: NetworkId) -> &mut NetworkState {
+        self.networks.entry(network).or_default()
+    }
+
+    /// The facts about `network` that did not expire by `now`.
+    pub fn findings(&self, network: &NetworkId, now: SystemTime) -> Findings {
+        self.get(network)
+            .map(|state| state.findings(now))
+            .unwrap_or_default()
+    }
+
+    /// Records the facts of `findings` for `network`, valid until `expires`.
+    pub fn record(&mut self, network: NetworkId, findings: &Findings, expires: SystemTime) {
+        self.get_mut(network).record(findings, expires);
+    }
+
+    /// Removes the networks all of whose facts expired by `now`.
+    pub fn remove_expired(&mut self, now: SystemTime) {
+        self.networks.retain(|_, state| !state.is_expired(now));
+    }
+
+    /// Iterates over all networks, e.g. to persist them.
+    pub fn iter(&self) -> impl Iterator<Item = (&NetworkId, &NetworkState)> {
+        self.networks.iter()
+    }
+}
@@ -0,0 +1,88 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// An IP address prefix, e.g. `2001:db8::/32` or `192.0.2.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawIpPrefix"))]
+pub struct IpPrefix {
+    address: IpAddr,
+    len: u8,
+}
+
+/// The fields of an [`IpPrefix`] as persisted, validated by [`IpPrefix::new`]
+/// on deserialization.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawIpPrefix {
+    address: IpAddr,
+    len: u8,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RawIpPrefix> for IpPrefix {
+    type Error = InvalidPrefixLen;
+
+    fn try_from(raw: RawIpPrefix) -> Result<Self, Self::Error> {
+        Self::new(raw.address, raw.len).ok_or(InvalidPrefixLen(raw.len))
+    }
+}
+
+/// The prefix length exceeds the length of the address family.
+#[cfg(feature = "serde")]
+#[derive(thiserror::Error, Debug)]
+#[error("invalid prefix length {0}")]
+struct InvalidPrefixLen(u8);
+
+impl IpPrefix {
+    /// Creates a new prefix, masking off the host bits of `address`.
+    ///
+    /// Returns [`None`] if `len` exceeds the length of the address family.
+    pub fn new(address: IpAddr, len: u8) -> Option<Self> {
+        let address = match address {
+            IpAddr::V4(v4) => {
+                if len > 32 {
+                    return None;
+                }
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask_v4(len)))
+            }
+            IpAddr::V6(v6) => {
+                if len > 128 {
+                    return None;
+                }
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask_v6(len)))
+            }
+        };
+        Some(Self { address, len })
+    }
+
+    /// The network address of the prefix.
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    /// The number of leading bits of the prefix.
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    /// Whether `ip` is part of this prefix.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(prefix), IpAddr::V4(ip)) => {
+                u32::from(ip) & mask_v4(self.len) == u32::from(prefix)
+            }
+            (IpAddr::V6(prefix), IpAddr::V6(ip)) => {
+                u128::from(ip) & mask_v6(self.len) == u128::from(prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask_v4(len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0)
+}
+
+fn mask_v6(len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0)
+}
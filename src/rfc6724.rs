@@ -0,0 +1,279 @@
+//! Destination address selection as specified in [RFC
+//! 6724](https://datatracker.ietf.org/doc/html/rfc6724), used to sort
+//! endpoints, see
+//! <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-5>.
+//!
+//! Rules 4 (home addresses) and 7 (native transport) of destination address
+//! selection are not implemented, as neither is known to the caller in the
+//! common case. Source address selection is approximated, see
+//! `select_source`.
+
+use std::cmp::Ordering;
+use std::net::{IpAddr, Ipv6Addr};
+
+/// A source address of the local host, used for destination address
+/// selection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceAddress {
+    pub address: IpAddr,
+    /// Whether the address is deprecated, e.g. because its preferred lifetime
+    /// expired.
+    pub deprecated: bool,
+}
+
+impl From<IpAddr> for SourceAddress {
+    fn from(address: IpAddr) -> Self {
+        Self {
+            address,
+            deprecated: false,
+        }
+    }
+}
+
+/// Multicast scope values, see RFC 6724 Section 3.1.
+const SCOPE_LINK_LOCAL: u8 = 0x2;
+const SCOPE_SITE_LOCAL: u8 = 0x5;
+const SCOPE_GLOBAL: u8 = 0xe;
+
+/// The default policy table.
+///
+/// <https://datatracker.ietf.org/doc/html/rfc6724#section-2.1>
+const POLICY_TABLE: &[(Ipv6Addr, u8, u8, u8)] = &[
+    // (prefix, prefix length, precedence, label)
+    (Ipv6Addr::LOCALHOST, 128, 50, 0),
+    (Ipv6Addr::UNSPECIFIED, 0, 40, 1),
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0), 96, 35, 4),
+    (Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0), 16, 30, 2),
+    (Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0), 32, 5, 5),
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7, 3, 13),
+    (Ipv6Addr::UNSPECIFIED, 96, 1, 3),
+    (Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0), 10, 1, 11),
+    (Ipv6Addr::new(0x3ffe, 0, 0, 0, 0, 0, 0, 0), 16, 1, 12),
+];
+
+/// Precedence of IPv4 addresses when IPv4 is preferred.
+///
+/// <https://datatracker.ietf.org/doc/html/rfc6724#section-10.3>
+const PREFER_V4_PRECEDENCE: u8 = 100;
+
+/// Destination address selection rules 1 to 8.
+///
+/// Rule 9 is split out into [`cmp_longest_matching_prefix`], as Happy
+/// Eyeballs adds its own rules between rules 8 and 9.
+///
+/// <https://datatracker.ietf.org/doc/html/rfc6724#section-6>
+pub(crate) fn cmp_destinations(
+    a: IpAddr,
+    b: IpAddr,
+    sources: &[SourceAddress],
+    prefer_v4: bool,
+) -> Ordering {
+    let source_a = select_source(a, sources);
+    let source_b = select_source(b, sources);
+
+    // Rule 1: Avoid unusable destinations.
+    let order = source_a.is_none().cmp(&source_b.is_none());
+    if order != Ordering::Equal {
+        return order;
+    }
+    let (Some(source_a), Some(source_b)) = (source_a, source_b) else {
+        return Ordering::Equal;
+    };
+
+    // Rule 2: Prefer matching scope.
+    let matching_scope = |d: IpAddr, s: &SourceAddress| scope(d) == scope(s.address);
+    let order = matching_scope(b, source_b).cmp(&matching_scope(a, source_a));
+    if order != Ordering::Equal {
+        return order;
+    }
+
+    // Rule 3: Avoid deprecated addresses.
+    let order = source_a.deprecated.cmp(&source_b.deprecated);
+    if order != Ordering::Equal {
+        return order;
+    }
+
+    // Rule 4: Prefer home addresses. Skipped, Mobile IPv6 home and care-of
+    // addresses are not part of `SourceAddress`.
+
+    // Rule 5: Prefer matching label.
+    let matching_label = |d: IpAddr, s: &SourceAddress| label(d) == label(s.address);
+    let order = matching_label(b, source_b).cmp(&matching_label(a, source_a));
+    if order != Ordering::Equal {
+        return order;
+    }
+
+    // Rule 6: Prefer higher precedence.
+    let order = precedence(b, prefer_v4).cmp(&precedence(a, prefer_v4));
+    if order != Ordering::Equal {
+        return order;
+    }
+
+    // Rule 7: Prefer native transport. Skipped, whether a source address is
+    // reached via encapsulation is not part of `SourceAddress`.
+
+    // Rule 8: Prefer smaller scope.
+    scope(a).cmp(&scope(b))
+}
+
+/// Destination address selection rule 9: Use longest matching prefix.
+///
+/// Only applies to destinations of the same address family.
+///
+/// <https://datatracker.ietf.org/doc/html/rfc6724#section-6>
+pub(crate) fn cmp_longest_matching_prefix(
+    a: IpAddr,
+    b: IpAddr,
+    sources: &[SourceAddress],
+) -> Ordering {
+    if a.is_ipv6() != b.is_ipv6() {
+        return Ordering::Equal;
+    }
+    match (select_source(a, sources), select_source(b, sources)) {
+        (Some(source_a), Some(source_b)) => {
+            matching_prefix_len(source_b.address, b).cmp(&matching_prefix_len(source_a.address, a))
+        }
+        _ => Ordering::Equal,
+    }
+}
+
+/// Simplified source address selection, see RFC 6724 Section 5.
+///
+/// Approximates the source address the operating system would pick for
+/// `destination`, applying rules 1 (same address), 2 (appropriate scope), 3
+/// (avoid deprecated), 6 (matching label) and 8 (longest matching prefix).
+fn select_source(destination: IpAddr, sources: &[SourceAddress]) -> Option<&SourceAddress> {
+    sources
+        .iter()
+        .filter(|s| s.address.is_ipv6() == destination.is_ipv6())
+        .min_by(|a, b| {
+            // Rule 1: Prefer same address.
+            let order = (b.address == destination).cmp(&(a.address == destination));
+            if order != Ordering::Equal {
+                return order;
+            }
+
+            // Rule 2: Prefer appropriate scope.
+            let (scope_a, scope_b) = (scope(a.address), scope(b.address));
+            let scope_d = scope(destination);
+            if scope_a < scope_b {
+                return if scope_a < scope_d {
+                    Ordering::Greater
+                } else {
+                    Ordering::Less
+                };
+            }
+            if scope_b < scope_a {
+                return if scope_b < scope_d {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                };
+            }
+
+            // Rule 3: Avoid deprecated addresses.
+            let order = a.deprecated.cmp(&b.deprecated);
+            if order != Ordering::Equal {
+                return order;
+            }
+
+            // Rule 6: Prefer matching label.
+            let label_d = label(destination);
+            let order = (label(b.address) == label_d).cmp(&(label(a.address) == label_d));
+            if order != Ordering::Equal {
+                return order;
+            }
+
+            // Rule 8: Use longest matching prefix.
+            matching_prefix_len(b.address, destination)
+                .cmp(&matching_prefix_len(a.address, destination))
+        })
+}
+
+/// <https://datatracker.ietf.org/doc/html/rfc6724#section-3.1>
+fn scope(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(v4) => {
+            // > IPv4 addresses are assigned scopes as follows: IPv4
+            // > auto-configuration addresses [RFC3927], which have the prefix
+            // > 169.254/16, are assigned link-local scope. IPv4 loopback
+            // > addresses [RFC1122], which have the prefix 127/8, are assigned
+            // > link-local scope. Other IPv4 addresses are assigned global scope.
+            //
+            // <https://datatracker.ietf.org/doc/html/rfc6724#section-3.2>
+            if v4.is_link_local() || v4.is_loopback() {
+                SCOPE_LINK_LOCAL
+            } else {
+                SCOPE_GLOBAL
+            }
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return scope(IpAddr::V4(v4));
+            }
+            if v6.is_multicast() {
+                return (v6.segments()[0] & 0x000f) as u8;
+            }
+            if v6.is_loopback() || v6.is_unicast_link_local() {
+                return SCOPE_LINK_LOCAL;
+            }
+            if v6.segments()[0] & 0xffc0 == 0xfec0 {
+                return SCOPE_SITE_LOCAL;
+            }
+            SCOPE_GLOBAL
+        }
+    }
+}
+
+/// Entry of the policy table with the longest prefix matching `ip`.
+fn policy(ip: IpAddr) -> (u8, u8) {
+    let ip = match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+    POLICY_TABLE
+        .iter()
+        .filter(|(prefix, len, _, _)| {
+            common_prefix_len(IpAddr::V6(*prefix), IpAddr::V6(ip)) >= u32::from(*len)
+        })
+        .max_by_key(|(_, len, _, _)| *len)
+        .map(|(_, _, precedence, label)| (*precedence, *label))
+        .expect("::/0 matches all addresses")
+}
+
+fn precedence(ip: IpAddr, prefer_v4: bool) -> u8 {
+    let is_v4 = match ip {
+        IpAddr::V4(_) => true,
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().is_some(),
+    };
+    if prefer_v4 && is_v4 {
+        return PREFER_V4_PRECEDENCE;
+    }
+    policy(ip).0
+}
+
+fn label(ip: IpAddr) -> u8 {
+    policy(ip).1
+}
+
+/// > CommonPrefixLen(S, D) is defined as the length of the longest prefix
+/// > (looking at the most significant, or leftmost, bits) that the two
+/// > addresses have in common, up to the length of S's prefix.
+///
+/// <https://datatracker.ietf.org/doc/html/rfc6724#section-2.2>
+///
+/// The prefix length of the source address is unknown, thus assumes the
+/// common 64 bits for IPv6.
+fn matching_prefix_len(source: IpAddr, destination: IpAddr) -> u32 {
+    let len = common_prefix_len(source, destination);
+    if source.is_ipv6() { len.min(64) } else { len }
+}
+
+/// Length of the longest common prefix of `a` and `b`.
+fn common_prefix_len(a: IpAddr, b: IpAddr) -> u32 {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) ^ u32::from(b)).leading_zeros(),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a) ^ u128::from(b)).leading_zeros(),
+        _ => 0,
+    }
+}
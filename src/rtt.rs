@@ -0,0 +1,60 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::{AddressFamily, IpPrefix};
+
+/// What an RTT estimate applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RttKey {
+    /// A single address.
+    Address(IpAddr),
+    /// All addresses within a prefix.
+    Prefix(IpPrefix),
+    /// All addresses of an address family.
+    Family(AddressFamily),
+}
+
+/// Round-trip time estimates, e.g. from previous connections.
+///
+/// Used to derive the Connection Attempt Delay:
+///
+/// > If the client has historical RTT data gathered from other connections
+/// > to the same host or prefix, it can use this information to influence
+/// > its delay.
+///
+/// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-6>
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RttEstimates {
+    estimates: Vec<(RttKey, Duration)>,
+}
+
+impl RttEstimates {
+    /// Sets the estimate for `key`, replacing any previous estimate.
+    pub fn insert(&mut self, key: RttKey, rtt: Duration) {
+        match self.estimates.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, existing)) => *existing = rtt,
+            None => self.estimates.push((key, rtt)),
+        }
+    }
+
+    /// Returns the most specific estimate for `ip`.
+    ///
+    /// An estimate for the address itself takes precedence over the estimate
+    /// of the longest matching prefix, which in turn takes precedence over the
+    /// estimate for the address family.
+    pub fn get(&self, ip: IpAddr) -> Option<Duration> {
+        let mut best: Option<(u16, Duration)> = None;
+        for (key, rtt) in &self.estimates {
+            let specificity = match key {
+                RttKey::Address(address) if *address == ip => u16::MAX,
+                RttKey::Prefix(prefix) if prefix.contains(ip) => u16::from(prefix.prefix_len()) + 1,
+                RttKey::Family(family) if *family == AddressFamily::of(ip) => 0,
+                _ => continue,
+            };
+            if best.is_none_or(|(s, _)| specificity > s) {
+                best = Some((specificity, *rtt));
+            }
+        }
+        best.map(|(_, rtt)| rtt)
+    }
+}
@@ -1,13 +1,20 @@
 use std::{
     collections::HashSet,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
-    time::Instant,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant, SystemTime},
 };
 
 use happy_eyeballs::{
-    AltSvc, CONNECTION_ATTEMPT_DELAY, ConnectionAttemptHttpVersions, DnsRecordType, DnsResult,
-    Endpoint, HappyEyeballs, HttpVersion, HttpVersions, Id, Input, IpPreference, NetworkConfig,
-    Output, RESOLUTION_DELAY,
+    AddressFamily, AltSvc, AltSvcCache, AltSvcFrame, AltSvcUpdate, AttemptBudget,
+    BROKEN_ALTERNATIVE_INITIAL_DELAY, BROKEN_ALTERNATIVE_MAX_DELAY, BrokenAlternatives,
+    CONNECTION_ATTEMPT_DELAY, ConnectionAttemptHttpVersions, ConnectionHistory,
+    DEFAULT_ALT_SVC_MAX_AGE, DEFAULT_CONNECTION_ATTEMPT_TIMEOUT, DEFAULT_DNS_TIMEOUT,
+    DnsRecordType, DnsResult, EchPolicy, Endpoint, EndpointSource, FailureReason, Findings,
+    HappyEyeballs, HistoryKey, HistoryRecord, HttpVersion, HttpVersions, Id, Input, IpPreference,
+    IpPrefix, Limits, MAX_ALT_SVC_MAX_AGE, NetworkConfig, NetworkId, NetworkStateStore, Origin,
+    Outcome, Output, ProtocolRacing, Provenance, RESOLUTION_DELAY, RttEstimates, RttKey,
+    SourceAddress, TargetName, Timing, parse_alt_svc_header, parse_h2_altsvc_frame,
+    parse_h3_altsvc_frame,
 };
 
 const HOSTNAME: &str = "example.com";
@@ -16,7 +23,13 @@ const V6_ADDR: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
 const V6_ADDR_2: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
 const V6_ADDR_3: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 3);
 const V4_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const V4_ADDR_2: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
 const ECH_CONFIG: &[u8] = &[1, 2, 3, 4, 5];
+/// Provenance of an address hint of an HTTPS record with priority 1.
+const HINT: Provenance = Provenance {
+    source: EndpointSource::AddressHint,
+    service_priority: Some(1),
+};
 
 trait HappyEyeballsExt {
     fn expect(&mut self, input_output: Vec<(Option<Input>, Option<Output>)>, now: Instant);
@@ -185,65 +198,58 @@ fn out_send_dns_a(id: Id) -> Output {
 fn out_attempt_v6_h1_h2(id: Id) -> Output {
     Output::AttemptConnection {
         id,
-        endpoint: Endpoint {
-            address: SocketAddr::new(V6_ADDR.into(), PORT),
-            protocol: ConnectionAttemptHttpVersions::H2OrH1,
-            ech_config: None,
-        },
+        endpoint: endpoint(V6_ADDR.into(), ConnectionAttemptHttpVersions::H2OrH1),
     }
 }
 
 fn out_attempt_v6_h2(id: Id) -> Output {
     Output::AttemptConnection {
         id,
-        endpoint: Endpoint {
-            address: SocketAddr::new(V6_ADDR.into(), PORT),
-            protocol: ConnectionAttemptHttpVersions::H2,
-            ech_config: None,
-        },
+        endpoint: endpoint(V6_ADDR.into(), ConnectionAttemptHttpVersions::H2),
     }
 }
 
 fn out_attempt_v6_h3(id: Id) -> Output {
     Output::AttemptConnection {
         id,
-        endpoint: Endpoint {
-            address: SocketAddr::new(V6_ADDR.into(), PORT),
-            protocol: ConnectionAttemptHttpVersions::H3,
-            ech_config: None,
-        },
+        endpoint: endpoint(V6_ADDR.into(), ConnectionAttemptHttpVersions::H3),
     }
 }
 
 fn out_attempt_v4_h1_h2(id: Id) -> Output {
     Output::AttemptConnection {
         id,
-        endpoint: Endpoint {
-            address: SocketAddr::new(V4_ADDR.into(), PORT),
-            protocol: ConnectionAttemptHttpVersions::H2OrH1,
-            ech_config: None,
-        },
+        endpoint: endpoint(V4_ADDR.into(), ConnectionAttemptHttpVersions::H2OrH1),
     }
 }
 
 fn out_attempt_v4_h2(id: Id) -> Output {
     Output::AttemptConnection {
         id,
-        endpoint: Endpoint {
-            address: SocketAddr::new(V4_ADDR.into(), PORT),
-            protocol: ConnectionAttemptHttpVersions::H2,
-            ech_config: None,
-        },
+        endpoint: endpoint(V4_ADDR.into(), ConnectionAttemptHttpVersions::H2),
     }
 }
 
 fn out_attempt_v4_h3(id: Id) -> Output {
     Output::AttemptConnection {
         id,
-        endpoint: Endpoint {
-            address: SocketAddr::new(V4_ADDR.into(), PORT),
-            protocol: ConnectionAttemptHttpVersions::H3,
-            ech_config: None,
+        endpoint: endpoint(V4_ADDR.into(), ConnectionAttemptHttpVersions::H3),
+    }
+}
+
+/// Endpoint of the origin at [`PORT`] without ECH.
+fn endpoint(ip: IpAddr, protocol: ConnectionAttemptHttpVersions) -> Endpoint {
+    Endpoint {
+        address: SocketAddr::new(ip, PORT),
+        protocol,
+        ech_config: None,
+        origin_host: HOSTNAME.to_string(),
+        target_name: HOSTNAME.into(),
+        ech_public_name: None,
+        ech_grease: false,
+        provenance: Provenance {
+            source: EndpointSource::DnsAnswer,
+            service_priority: None,
         },
     }
 }
@@ -367,6 +373,7 @@ mod section_4_hostname_resolution {
                     http_versions: HttpVersions::default(),
                     ip: IpPreference::DualStackPreferV6,
                     alt_svc: Vec::new(),
+                    ..NetworkConfig::default()
                 },
                 positive: in_dns_aaaa_positive(Id::from(1)),
                 preferred: None,
@@ -378,6 +385,7 @@ mod section_4_hostname_resolution {
                     http_versions: HttpVersions::default(),
                     ip: IpPreference::DualStackPreferV6,
                     alt_svc: Vec::new(),
+                    ..NetworkConfig::default()
                 },
                 positive: in_dns_a_positive(Id::from(2)),
                 preferred: Some(in_dns_aaaa_positive(Id::from(1))),
@@ -389,6 +397,7 @@ mod section_4_hostname_resolution {
                     http_versions: HttpVersions::default(),
                     ip: IpPreference::DualStackPreferV6,
                     alt_svc: Vec::new(),
+                    ..NetworkConfig::default()
                 },
                 positive: in_dns_a_positive(Id::from(2)),
                 preferred: Some(in_dns_aaaa_negative(Id::from(1))),
@@ -400,6 +409,7 @@ mod section_4_hostname_resolution {
                     http_versions: HttpVersions::default(),
                     ip: IpPreference::DualStackPreferV4,
                     alt_svc: Vec::new(),
+                    ..NetworkConfig::default()
                 },
                 positive: in_dns_a_positive(Id::from(2)),
                 preferred: None,
@@ -411,6 +421,7 @@ mod section_4_hostname_resolution {
                     http_versions: HttpVersions::default(),
                     ip: IpPreference::DualStackPreferV4,
                     alt_svc: Vec::new(),
+                    ..NetworkConfig::default()
                 },
                 positive: in_dns_aaaa_positive(Id::from(1)),
                 preferred: Some(in_dns_a_positive(Id::from(2))),
@@ -422,6 +433,7 @@ mod section_4_hostname_resolution {
                     http_versions: HttpVersions::default(),
                     ip: IpPreference::DualStackPreferV4,
                     alt_svc: Vec::new(),
+                    ..NetworkConfig::default()
                 },
                 positive: in_dns_aaaa_positive(Id::from(1)),
                 preferred: Some(in_dns_a_negative(Id::from(2))),
@@ -511,6 +523,36 @@ mod section_4_hostname_resolution {
         he.expect(vec![(None, Some(out_attempt_v4_h1_h2(Id::from(3))))], now);
     }
 
+    #[test]
+    fn custom_resolution_delay() {
+        const DELAY: Duration = Duration::from_millis(20);
+
+        let (mut now, mut he) = setup_with_config(NetworkConfig {
+            timing: Timing {
+                resolution_delay: DELAY,
+                ..Timing::default()
+            },
+            ..NetworkConfig::default()
+        });
+
+        he.expect(
+            vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
+                (
+                    Some(in_dns_a_positive(Id::from(2))),
+                    Some(Output::Timer { duration: DELAY }),
+                ),
+            ],
+            now,
+        );
+
+        now += DELAY;
+
+        he.expect(vec![(None, Some(out_attempt_v4_h1_h2(Id::from(3))))], now);
+    }
+
     /// Start of the Resolution Delay is not the first DNS query is sent, but
     /// the first response received.
     ///
@@ -585,7 +627,13 @@ mod section_4_hostname_resolution {
                 ),
                 (
                     Some(in_dns_https_positive_v6_hints(Id::from(0))),
-                    Some(out_attempt_v6_h3(Id::from(3))),
+                    Some(Output::AttemptConnection {
+                        id: Id::from(3),
+                        endpoint: Endpoint {
+                            provenance: HINT,
+                            ..endpoint(V6_ADDR.into(), ConnectionAttemptHttpVersions::H3)
+                        },
+                    }),
                 ),
             ],
             now,
@@ -671,11 +719,7 @@ mod section_4_hostname_resolution {
                 None,
                 Some(Output::AttemptConnection {
                     id: Id::from(4),
-                    endpoint: Endpoint {
-                        address: SocketAddr::new(V6_ADDR_2.into(), PORT),
-                        protocol: ConnectionAttemptHttpVersions::H2OrH1,
-                        ech_config: None,
-                    },
+                    endpoint: endpoint(V6_ADDR_2.into(), ConnectionAttemptHttpVersions::H2OrH1),
                 }),
             )],
             now,
@@ -699,23 +743,27 @@ mod section_4_hostname_resolution {
                     Some(in_dns_aaaa_negative(Id::from(1))),
                     Some(out_resolution_delay()),
                 ),
-                (Some(in_dns_a_negative(Id::from(2))), Some(Output::Failed)),
+                (
+                    Some(in_dns_a_negative(Id::from(2))),
+                    Some(Output::Failed(FailureReason::Exhausted)),
+                ),
             ],
             now,
         );
     }
 }
 
-// TODO: Move to own file?
-mod section_6_connection_attempts {
-    use happy_eyeballs::CONNECTION_ATTEMPT_DELAY;
+/// > 5. Grouping and Sorting
+///
+/// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-5>
+mod section_5_sorting {
+    use std::time::Duration;
 
     use super::*;
 
-    #[test]
-    fn connection_attempt_delay() {
-        let (mut now, mut he) = setup();
-
+    /// Runs DNS resolution with positive HTTPS (no ALPN), AAAA and A answers,
+    /// returning the first connection attempt.
+    pub(super) fn first_attempt(he: &mut HappyEyeballs, now: Instant) -> Option<Output> {
         he.expect(
             vec![
                 (None, Some(out_send_dns_https(Id::from(0)))),
@@ -725,25 +773,292 @@ mod section_6_connection_attempts {
                     Some(in_dns_https_positive_no_alpn(Id::from(0))),
                     Some(out_resolution_delay()),
                 ),
-                (
-                    Some(in_dns_aaaa_positive(Id::from(1))),
-                    Some(out_attempt_v6_h1_h2(Id::from(3))),
-                ),
                 (
                     Some(in_dns_a_positive(Id::from(2))),
-                    Some(out_connection_attempt_delay()),
+                    Some(out_resolution_delay()),
                 ),
             ],
             now,
         );
+        he.process_input(in_dns_aaaa_positive(Id::from(1)), now);
+        he.process_output(now)
+    }
 
-        now += CONNECTION_ATTEMPT_DELAY;
+    /// Destination Address Selection.
+    ///
+    /// <https://datatracker.ietf.org/doc/html/rfc6724#section-6>
+    #[test]
+    fn rfc6724_destination_address_selection() {
+        #[derive(Debug)]
+        struct Case {
+            sources: Vec<SourceAddress>,
+            aaaa: Vec<Ipv6Addr>,
+            a: Vec<Ipv4Addr>,
+            expected: IpAddr,
+        }
 
-        he.expect(vec![(None, Some(out_attempt_v4_h1_h2(Id::from(4))))], now);
+        const GLOBAL_SRC_V6: IpAddr =
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 0x100));
+        const LINK_LOCAL_SRC_V6: IpAddr =
+            IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x100));
+        const SRC_V4: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 100));
+        const ULA: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        const TEREDO: Ipv6Addr = Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 1);
+        const LINK_LOCAL: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        const SAME_SUBNET: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 1);
+        const SAME_SUBNET_2: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 0x101);
+        const SIX_TO_FOUR: Ipv6Addr = Ipv6Addr::new(0x2002, 0xc000, 0x0201, 0, 0, 0, 0, 1);
+        const SIX_TO_FOUR_SRC: IpAddr =
+            IpAddr::V6(Ipv6Addr::new(0x2002, 0xc000, 0x0264, 0, 0, 0, 0, 0x100));
+        const ULA_SRC: IpAddr = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x100));
+        const OTHER_NETWORK_V4: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+
+        let dual_stack = vec![GLOBAL_SRC_V6.into(), SRC_V4.into()];
+
+        let test_cases = vec![
+            // Rule 6: Global IPv6 has higher precedence than IPv4.
+            Case {
+                sources: dual_stack.clone(),
+                aaaa: vec![V6_ADDR],
+                a: vec![V4_ADDR],
+                expected: V6_ADDR.into(),
+            },
+            // Rule 6: IPv4 has higher precedence than ULA.
+            Case {
+                sources: dual_stack.clone(),
+                aaaa: vec![ULA],
+                a: vec![V4_ADDR],
+                expected: V4_ADDR.into(),
+            },
+            // Rule 6: IPv4 has higher precedence than Teredo.
+            Case {
+                sources: dual_stack.clone(),
+                aaaa: vec![TEREDO],
+                a: vec![V4_ADDR],
+                expected: V4_ADDR.into(),
+            },
+            // Rule 6: Native IPv6 has higher precedence than 6to4 and Teredo.
+            Case {
+                sources: dual_stack.clone(),
+                aaaa: vec![SIX_TO_FOUR, TEREDO, V6_ADDR],
+                a: vec![],
+                expected: V6_ADDR.into(),
+            },
+            // Rule 6: IPv4 has higher precedence than 6to4.
+            Case {
+                sources: dual_stack.clone(),
+                aaaa: vec![SIX_TO_FOUR],
+                a: vec![V4_ADDR],
+                expected: V4_ADDR.into(),
+            },
+            // Rule 5: A 6to4 source address prefers the 6to4 destination with
+            // matching label over the one with higher precedence.
+            Case {
+                sources: vec![SIX_TO_FOUR_SRC.into()],
+                aaaa: vec![V6_ADDR, SIX_TO_FOUR],
+                a: vec![],
+                expected: SIX_TO_FOUR.into(),
+            },
+            // Rule 5: Likewise, a ULA source address prefers a ULA
+            // destination.
+            Case {
+                sources: vec![ULA_SRC.into()],
+                aaaa: vec![V6_ADDR, ULA],
+                a: vec![],
+                expected: ULA.into(),
+            },
+            // Rule 1: No IPv4 source address, thus IPv4 unusable.
+            Case {
+                sources: vec![GLOBAL_SRC_V6.into()],
+                aaaa: vec![ULA],
+                a: vec![V4_ADDR],
+                expected: ULA.into(),
+            },
+            // Rule 2: Only a link-local IPv6 source address, thus prefer the
+            // link-local destination with matching scope.
+            Case {
+                sources: vec![LINK_LOCAL_SRC_V6.into(), SRC_V4.into()],
+                aaaa: vec![V6_ADDR, LINK_LOCAL],
+                a: vec![],
+                expected: LINK_LOCAL.into(),
+            },
+            // Rule 3: Deprecated source addresses for both destinations,
+            // thus down to precedence.
+            Case {
+                sources: vec![
+                    SourceAddress {
+                        address: GLOBAL_SRC_V6,
+                        deprecated: true,
+                    },
+                    SourceAddress {
+                        address: SRC_V4,
+                        deprecated: true,
+                    },
+                ],
+                aaaa: vec![V6_ADDR],
+                a: vec![V4_ADDR],
+                expected: V6_ADDR.into(),
+            },
+            // Rule 3: Avoid deprecated source addresses.
+            Case {
+                sources: vec![
+                    SourceAddress {
+                        address: GLOBAL_SRC_V6,
+                        deprecated: true,
+                    },
+                    SRC_V4.into(),
+                ],
+                aaaa: vec![V6_ADDR],
+                a: vec![V4_ADDR],
+                expected: V4_ADDR.into(),
+            },
+            // Rule 9: Use longest matching prefix.
+            Case {
+                sources: dual_stack.clone(),
+                aaaa: vec![V6_ADDR, SAME_SUBNET],
+                a: vec![],
+                expected: SAME_SUBNET.into(),
+            },
+            // Rule 9: The common prefix of IPv6 addresses is capped at the
+            // assumed /64 of the source address, thus a tie.
+            Case {
+                sources: dual_stack.clone(),
+                aaaa: vec![SAME_SUBNET, SAME_SUBNET_2],
+                a: vec![],
+                expected: SAME_SUBNET.into(),
+            },
+            // Rule 9: Also applies to IPv4.
+            Case {
+                sources: dual_stack.clone(),
+                aaaa: vec![],
+                a: vec![OTHER_NETWORK_V4, V4_ADDR],
+                expected: V4_ADDR.into(),
+            },
+        ];
+
+        for case in test_cases {
+            let (now, mut he) = setup_with_config(NetworkConfig {
+                source_addresses: case.sources.clone(),
+                ..NetworkConfig::default()
+            });
+
+            he.expect(
+                vec![
+                    (None, Some(out_send_dns_https(Id::from(0)))),
+                    (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                    (None, Some(out_send_dns_a(Id::from(2)))),
+                    (
+                        Some(in_dns_https_negative(Id::from(0))),
+                        Some(out_resolution_delay()),
+                    ),
+                    (
+                        Some(Input::DnsResult {
+                            id: Id::from(2),
+                            result: DnsResult::A(Ok(case.a.clone())),
+                        }),
+                        Some(out_resolution_delay()),
+                    ),
+                ],
+                now,
+            );
+            he.process_input(
+                Input::DnsResult {
+                    id: Id::from(1),
+                    result: DnsResult::Aaaa(Ok(case.aaaa.clone())),
+                },
+                now,
+            );
+            let endpoint = he.process_output(now).unwrap().attempt().unwrap();
+            assert_eq!(endpoint.address.ip(), case.expected, "{case:?}");
+        }
     }
 
+    /// > First Address Family Count (Section 5): The number of addresses
+    /// > belonging to the preferred address family (such as IPv6) that should
+    /// > be attempted before attempting the next address family.
+    ///
+    /// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-9>
     #[test]
-    fn never_try_same_attempt_twice() {
+    fn interleave_address_families() {
+        let test_cases: Vec<(usize, Vec<IpAddr>)> = vec![
+            (
+                1,
+                vec![
+                    V6_ADDR.into(),
+                    V4_ADDR.into(),
+                    V6_ADDR_2.into(),
+                    V4_ADDR_2.into(),
+                    V6_ADDR_3.into(),
+                ],
+            ),
+            (
+                2,
+                vec![
+                    V6_ADDR.into(),
+                    V6_ADDR_2.into(),
+                    V4_ADDR.into(),
+                    V6_ADDR_3.into(),
+                    V4_ADDR_2.into(),
+                ],
+            ),
+            (
+                5,
+                vec![
+                    V6_ADDR.into(),
+                    V6_ADDR_2.into(),
+                    V6_ADDR_3.into(),
+                    V4_ADDR.into(),
+                    V4_ADDR_2.into(),
+                ],
+            ),
+        ];
+
+        for (first_address_family_count, expected) in test_cases {
+            let (mut now, mut he) = setup_with_config(NetworkConfig {
+                first_address_family_count,
+                ..NetworkConfig::default()
+            });
+
+            he.expect(
+                vec![
+                    (None, Some(out_send_dns_https(Id::from(0)))),
+                    (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                    (None, Some(out_send_dns_a(Id::from(2)))),
+                    (
+                        Some(in_dns_https_negative(Id::from(0))),
+                        Some(out_resolution_delay()),
+                    ),
+                    (
+                        Some(Input::DnsResult {
+                            id: Id::from(2),
+                            result: DnsResult::A(Ok(vec![V4_ADDR, V4_ADDR_2])),
+                        }),
+                        Some(out_resolution_delay()),
+                    ),
+                ],
+                now,
+            );
+            he.process_input(
+                Input::DnsResult {
+                    id: Id::from(1),
+                    result: DnsResult::Aaaa(Ok(vec![V6_ADDR, V6_ADDR_2, V6_ADDR_3])),
+                },
+                now,
+            );
+
+            let mut attempted = vec![];
+            while let Some(Output::AttemptConnection { endpoint, .. }) = he.process_output(now) {
+                attempted.push(endpoint.address.ip());
+                now += CONNECTION_ATTEMPT_DELAY;
+            }
+            assert_eq!(attempted, expected, "{first_address_family_count}");
+        }
+    }
+
+    /// Interleaving applies within each protocol group, i.e. all QUIC
+    /// endpoints are attempted before the TCP endpoints.
+    #[test]
+    fn interleave_address_families_per_protocol() {
         let (mut now, mut he) = setup();
 
         he.expect(
@@ -752,97 +1067,235 @@ mod section_6_connection_attempts {
                 (None, Some(out_send_dns_aaaa(Id::from(1)))),
                 (None, Some(out_send_dns_a(Id::from(2)))),
                 (
-                    Some(in_dns_https_negative(Id::from(0))),
+                    Some(in_dns_https_positive(Id::from(0))),
                     Some(out_resolution_delay()),
                 ),
                 (
-                    Some(in_dns_a_negative(Id::from(2))),
+                    Some(in_dns_a_positive(Id::from(2))),
                     Some(out_resolution_delay()),
                 ),
-                (
-                    Some(in_dns_aaaa_positive(Id::from(1))),
-                    Some(out_attempt_v6_h1_h2(Id::from(3))),
-                ),
             ],
             now,
         );
+        he.process_input(
+            Input::DnsResult {
+                id: Id::from(1),
+                result: DnsResult::Aaaa(Ok(vec![V6_ADDR, V6_ADDR_2])),
+            },
+            now,
+        );
 
-        now += CONNECTION_ATTEMPT_DELAY;
+        let mut attempted = vec![];
+        while let Some(Output::AttemptConnection { endpoint, .. }) = he.process_output(now) {
+            attempted.push((endpoint.protocol, endpoint.address.ip()));
+            now += CONNECTION_ATTEMPT_DELAY;
+        }
+        assert_eq!(
+            attempted,
+            vec![
+                (ConnectionAttemptHttpVersions::H3, V6_ADDR.into()),
+                (ConnectionAttemptHttpVersions::H3, V4_ADDR.into()),
+                (ConnectionAttemptHttpVersions::H3, V6_ADDR_2.into()),
+                (ConnectionAttemptHttpVersions::H2, V6_ADDR.into()),
+                (ConnectionAttemptHttpVersions::H2, V4_ADDR.into()),
+                (ConnectionAttemptHttpVersions::H2, V6_ADDR_2.into()),
+            ]
+        );
+    }
 
-        he.expect(vec![(None, None)], now);
+    /// > If the client is stateful and has a history of expected round-trip
+    /// > times (RTTs) for the routes to access each address, it SHOULD add a
+    /// > Destination Address Selection rule between rules 8 and 9 that
+    /// > prefers addresses with lower RTTs.
+    ///
+    /// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-5>
+    #[test]
+    fn history_prefers_lower_rtt() {
+        let mut history = ConnectionHistory::default();
+        history.record_success(
+            HistoryKey::Address(V6_ADDR.into()),
+            Duration::from_millis(80),
+        );
+        history.record_success(
+            HistoryKey::Prefix(IpPrefix::new(V4_ADDR.into(), 24).unwrap()),
+            Duration::from_millis(20),
+        );
+
+        let (now, mut he) = setup_with_config(NetworkConfig {
+            history,
+            ..NetworkConfig::default()
+        });
+
+        assert_eq!(
+            first_attempt(&mut he, now),
+            Some(out_attempt_v4_h1_h2(Id::from(3)))
+        );
     }
 
     #[test]
-    fn successful_connection_cancels_others() {
+    fn history_demotes_failed_addresses() {
+        let mut history = ConnectionHistory::default();
+        history.record_failure(HistoryKey::Address(V6_ADDR.into()));
+
+        let (now, mut he) = setup_with_config(NetworkConfig {
+            history,
+            ..NetworkConfig::default()
+        });
+
+        assert_eq!(
+            first_attempt(&mut he, now),
+            Some(out_attempt_v4_h1_h2(Id::from(3)))
+        );
+    }
+
+    #[test]
+    fn history_records_race_outcome() {
         let (mut now, mut he) = setup();
 
+        assert_eq!(
+            first_attempt(&mut he, now),
+            Some(out_attempt_v6_h1_h2(Id::from(3)))
+        );
         he.expect(
-            vec![
-                (None, Some(out_send_dns_https(Id::from(0)))),
-                (None, Some(out_send_dns_aaaa(Id::from(1)))),
-                (None, Some(out_send_dns_a(Id::from(2)))),
-                (
-                    Some(in_dns_https_positive_no_alpn(Id::from(0))),
-                    Some(out_resolution_delay()),
-                ),
-                (
-                    Some(Input::DnsResult {
-                        id: Id::from(1),
-                        result: DnsResult::Aaaa(Ok(vec![V6_ADDR, V6_ADDR_2])),
-                    }),
-                    Some(out_attempt_v6_h1_h2(Id::from(3))),
-                ),
-                (
-                    Some(in_dns_a_positive(Id::from(2))),
-                    Some(out_connection_attempt_delay()),
-                ),
-            ],
+            vec![(
+                Some(in_connection_result_negative(Id::from(3))),
+                Some(out_attempt_v4_h1_h2(Id::from(4))),
+            )],
             now,
         );
 
-        now += CONNECTION_ATTEMPT_DELAY;
+        now += Duration::from_millis(30);
         he.expect(
             vec![(
-                None,
-                Some(Output::AttemptConnection {
-                    id: Id::from(4),
-                    endpoint: Endpoint {
-                        address: SocketAddr::new(V6_ADDR_2.into(), PORT),
-                        protocol: ConnectionAttemptHttpVersions::H2OrH1,
-                        ech_config: None,
-                    },
-                }),
+                Some(in_connection_result_positive(Id::from(4))),
+                Some(Output::Succeeded),
             )],
             now,
         );
 
-        now += CONNECTION_ATTEMPT_DELAY;
-        he.expect(vec![(None, Some(out_attempt_v4_h1_h2(Id::from(5))))], now);
+        assert_eq!(
+            he.history().get(V6_ADDR.into()),
+            Some(&HistoryRecord {
+                rtt: None,
+                successes: 0,
+                failures: 1,
+            })
+        );
+        assert_eq!(
+            he.history().get(V4_ADDR.into()),
+            Some(&HistoryRecord {
+                rtt: Some(Duration::from_millis(30)),
+                successes: 1,
+                failures: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn history_drops_least_recently_updated() {
+        let mut history = ConnectionHistory::default();
+        history.record_failure(HistoryKey::Address(V6_ADDR.into()));
+        history.record_failure(HistoryKey::Address(V4_ADDR.into()));
+        for i in 0..ConnectionHistory::MAX_RECORDS - 2 {
+            let ip = Ipv6Addr::from(0x2001_0db8_ffff_u128 << 80 | i as u128);
+            history.record_failure(HistoryKey::Address(ip.into()));
+        }
+        // Updating a record keeps it.
+        history.record_failure(HistoryKey::Address(V6_ADDR.into()));
+        history.record_failure(HistoryKey::Address(V6_ADDR_2.into()));
+
+        assert_eq!(history.iter().count(), ConnectionHistory::MAX_RECORDS);
+        assert!(history.get(V4_ADDR.into()).is_none());
+        assert_eq!(history.get(V6_ADDR.into()).map(|r| r.failures), Some(2));
+        assert!(history.get(V6_ADDR_2.into()).is_some());
+    }
+
+    /// Findings of a previous race on the same network apply to the next race
+    /// until they expire.
+    #[test]
+    fn network_state_across_races() {
+        let network = NetworkId::from("home-wifi");
+        let wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut store = NetworkStateStore::default();
+        store.record(
+            network.clone(),
+            &Findings {
+                ipv6_blackhole: true,
+                ..Findings::default()
+            },
+            wall + Duration::from_secs(3600),
+        );
+
+        let test_cases = vec![
+            (wall, Some(out_attempt_v4_h1_h2(Id::from(3)))),
+            (
+                wall + Duration::from_secs(7200),
+                Some(out_attempt_v6_h1_h2(Id::from(3))),
+            ),
+        ];
+
+        for (wall, expected) in test_cases {
+            let (now, mut he) = setup_with_config(NetworkConfig {
+                known_findings: store.findings(&network, wall),
+                ..NetworkConfig::default()
+            });
+            assert_eq!(first_attempt(&mut he, now), expected);
+            // Not learned anew, thus not renewed.
+            assert_eq!(he.findings(), &Findings::default());
+        }
+
+        store.remove_expired(wall + Duration::from_secs(7200));
+        assert_eq!(store.get(&network), None);
+    }
+
+    /// A network known to block QUIC has QUIC endpoints attempted last.
+    #[test]
+    fn network_state_quic_blocked() {
+        let (now, mut he) = setup_with_config(NetworkConfig {
+            known_findings: Findings {
+                quic_demoted: true,
+                ..Findings::default()
+            },
+            ..NetworkConfig::default()
+        });
+
         he.expect(
             vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
                 (
-                    Some(in_connection_result_positive(Id::from(3))),
-                    Some(Output::CancelConnection(SocketAddr::new(
-                        V6_ADDR_2.into(),
-                        PORT,
-                    ))),
+                    Some(in_dns_https_positive(Id::from(0))),
+                    Some(out_resolution_delay()),
                 ),
                 (
-                    None,
-                    Some(Output::CancelConnection(SocketAddr::new(
-                        V4_ADDR.into(),
-                        PORT,
-                    ))),
+                    Some(in_dns_a_positive(Id::from(2))),
+                    Some(out_resolution_delay()),
+                ),
+                (
+                    Some(in_dns_aaaa_positive(Id::from(1))),
+                    Some(out_attempt_v6_h2(Id::from(3))),
                 ),
-                (None, Some(Output::Succeeded)),
             ],
             now,
         );
+        assert!(!he.findings().quic_demoted);
     }
+}
+
+// TODO: Move to own file?
+mod section_6_connection_attempts {
+    use std::time::Duration;
+
+    use happy_eyeballs::{
+        CONNECTION_ATTEMPT_DELAY, MAX_CONNECTION_ATTEMPT_DELAY, MIN_CONNECTION_ATTEMPT_DELAY,
+    };
+
+    use super::*;
 
     #[test]
-    fn failed_connection_tries_next_immediately() {
-        let (now, mut he) = setup();
+    fn connection_attempt_delay() {
+        let (mut now, mut he) = setup();
 
         he.expect(
             vec![
@@ -865,18 +1318,22 @@ mod section_6_connection_attempts {
             now,
         );
 
-        he.expect(
-            vec![(
-                Some(in_connection_result_negative(Id::from(3))),
-                Some(out_attempt_v4_h1_h2(Id::from(4))),
-            )],
-            now,
-        );
+        now += CONNECTION_ATTEMPT_DELAY;
+
+        he.expect(vec![(None, Some(out_attempt_v4_h1_h2(Id::from(4))))], now);
     }
 
     #[test]
-    fn successful_connection_emits_succeeded() {
-        let (now, mut he) = setup();
+    fn custom_connection_attempt_delay() {
+        const DELAY: Duration = Duration::from_millis(500);
+
+        let (mut now, mut he) = setup_with_config(NetworkConfig {
+            timing: Timing {
+                connection_attempt_delay: DELAY,
+                ..Timing::default()
+            },
+            ..NetworkConfig::default()
+        });
 
         he.expect(
             vec![
@@ -892,17 +1349,166 @@ mod section_6_connection_attempts {
                     Some(out_attempt_v6_h1_h2(Id::from(3))),
                 ),
                 (
-                    Some(in_connection_result_positive(Id::from(3))),
-                    Some(Output::Succeeded),
+                    Some(in_dns_a_positive(Id::from(2))),
+                    Some(Output::Timer { duration: DELAY }),
                 ),
             ],
             now,
         );
+
+        now += CONNECTION_ATTEMPT_DELAY;
+        he.expect(
+            vec![(
+                None,
+                Some(Output::Timer {
+                    duration: DELAY - CONNECTION_ATTEMPT_DELAY,
+                }),
+            )],
+            now,
+        );
+
+        now += DELAY - CONNECTION_ATTEMPT_DELAY;
+        he.expect(vec![(None, Some(out_attempt_v4_h1_h2(Id::from(4))))], now);
     }
 
+    /// > Minimum Connection Attempt Delay (Section 6): The minimum time to
+    /// > wait between connection attempts. Recommended to be 100
+    /// > milliseconds. MUST NOT be less than 10 milliseconds.
+    /// >
+    /// > Maximum Connection Attempt Delay (Section 6): The maximum time to
+    /// > wait between connection attempts. Recommended to be 2 seconds.
+    ///
+    /// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-9>
     #[test]
-    fn succeeded_keeps_emitting_succeeded() {
-        let (now, mut he) = setup();
+    fn connection_attempt_delay_clamped() {
+        let test_cases = [
+            // Below the minimum.
+            (
+                Timing {
+                    connection_attempt_delay: Duration::from_millis(1),
+                    ..Timing::default()
+                },
+                MIN_CONNECTION_ATTEMPT_DELAY,
+            ),
+            // Above the maximum.
+            (
+                Timing {
+                    connection_attempt_delay: Duration::from_secs(10),
+                    ..Timing::default()
+                },
+                MAX_CONNECTION_ATTEMPT_DELAY,
+            ),
+            // Minimum configured below the 10 milliseconds floor.
+            (
+                Timing {
+                    connection_attempt_delay: Duration::ZERO,
+                    min_connection_attempt_delay: Duration::ZERO,
+                    ..Timing::default()
+                },
+                Duration::from_millis(10),
+            ),
+        ];
+
+        for (timing, expected) in test_cases {
+            let (now, mut he) = setup_with_config(NetworkConfig {
+                timing,
+                ..NetworkConfig::default()
+            });
+
+            he.expect(
+                vec![
+                    (None, Some(out_send_dns_https(Id::from(0)))),
+                    (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                    (None, Some(out_send_dns_a(Id::from(2)))),
+                    (
+                        Some(in_dns_https_positive_no_alpn(Id::from(0))),
+                        Some(out_resolution_delay()),
+                    ),
+                    (
+                        Some(in_dns_aaaa_positive(Id::from(1))),
+                        Some(out_attempt_v6_h1_h2(Id::from(3))),
+                    ),
+                    (
+                        Some(in_dns_a_positive(Id::from(2))),
+                        Some(Output::Timer { duration: expected }),
+                    ),
+                ],
+                now,
+            );
+        }
+    }
+
+    /// > If the client has historical RTT data gathered from other
+    /// > connections to the same host or prefix, it can use this information
+    /// > to influence its delay.
+    ///
+    /// <https://www.ietf.org/archive/id/draft-ietf-happy-happyeyeballs-v3-02.html#section-6>
+    #[test]
+    fn rtt_based_connection_attempt_delay() {
+        let mut rtt_estimates = RttEstimates::default();
+        rtt_estimates.insert(
+            RttKey::Family(AddressFamily::V6),
+            Duration::from_millis(300),
+        );
+        rtt_estimates.insert(
+            RttKey::Prefix(IpPrefix::new(V6_ADDR.into(), 64).unwrap()),
+            Duration::from_millis(150),
+        );
+        rtt_estimates.insert(RttKey::Address(V6_ADDR_2.into()), Duration::from_millis(5));
+
+        let test_cases = [
+            // Prefix estimate takes precedence over family estimate.
+            (V6_ADDR, Duration::from_millis(150)),
+            // Address estimate takes precedence, clamped to the minimum.
+            (V6_ADDR_2, MIN_CONNECTION_ATTEMPT_DELAY),
+            // Only the family estimate applies.
+            (
+                Ipv6Addr::new(0x2001, 0xdb9, 0, 0, 0, 0, 0, 1),
+                Duration::from_millis(300),
+            ),
+        ];
+
+        for (address, expected) in test_cases {
+            let (now, mut he) = setup_with_config(NetworkConfig {
+                rtt_estimates: rtt_estimates.clone(),
+                ..NetworkConfig::default()
+            });
+
+            he.expect(
+                vec![
+                    (None, Some(out_send_dns_https(Id::from(0)))),
+                    (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                    (None, Some(out_send_dns_a(Id::from(2)))),
+                    (
+                        Some(in_dns_https_positive_no_alpn(Id::from(0))),
+                        Some(out_resolution_delay()),
+                    ),
+                    (
+                        Some(Input::DnsResult {
+                            id: Id::from(1),
+                            result: DnsResult::Aaaa(Ok(vec![address])),
+                        }),
+                        Some(Output::AttemptConnection {
+                            id: Id::from(3),
+                            endpoint: endpoint(
+                                address.into(),
+                                ConnectionAttemptHttpVersions::H2OrH1,
+                            ),
+                        }),
+                    ),
+                    (
+                        Some(in_dns_a_positive(Id::from(2))),
+                        Some(Output::Timer { duration: expected }),
+                    ),
+                ],
+                now,
+            );
+        }
+    }
+
+    #[test]
+    fn rtt_estimate_input() {
+        let (mut now, mut he) = setup();
 
         he.expect(
             vec![
@@ -917,21 +1523,448 @@ mod section_6_connection_attempts {
                     Some(in_dns_aaaa_positive(Id::from(1))),
                     Some(out_attempt_v6_h1_h2(Id::from(3))),
                 ),
+                (
+                    Some(in_dns_a_positive(Id::from(2))),
+                    Some(out_connection_attempt_delay()),
+                ),
+                (
+                    Some(Input::RttEstimate {
+                        key: RttKey::Address(V6_ADDR.into()),
+                        rtt: Duration::from_millis(120),
+                    }),
+                    Some(Output::Timer {
+                        duration: Duration::from_millis(120),
+                    }),
+                ),
+            ],
+            now,
+        );
+
+        now += Duration::from_millis(120);
+        he.expect(vec![(None, Some(out_attempt_v4_h1_h2(Id::from(4))))], now);
+    }
+
+    /// Drives the state machine through its timers, recording when each
+    /// connection attempt starts relative to `start`.
+    fn attempt_schedule(
+        he: &mut HappyEyeballs,
+        start: Instant,
+    ) -> Vec<(Duration, ConnectionAttemptHttpVersions, IpAddr)> {
+        let mut now = start;
+        let mut schedule = vec![];
+        loop {
+            match he.process_output(now) {
+                Some(Output::AttemptConnection { endpoint, .. }) => {
+                    schedule.push((now - start, endpoint.protocol, endpoint.address.ip()));
+                }
+                Some(Output::Timer { duration }) => now += duration,
+                _ => return schedule,
+            }
+        }
+    }
+
+    #[test]
+    fn protocol_racing() {
+        const HEAD_START: Duration = Duration::from_millis(100);
+        const H3: ConnectionAttemptHttpVersions = ConnectionAttemptHttpVersions::H3;
+        const H2: ConnectionAttemptHttpVersions = ConnectionAttemptHttpVersions::H2;
+        const DELAY: Duration = CONNECTION_ATTEMPT_DELAY;
+
+        let test_cases = vec![
+            (
+                ProtocolRacing::QuicFirst,
+                vec![
+                    (Duration::ZERO, H3, V6_ADDR.into()),
+                    (DELAY, H3, V4_ADDR.into()),
+                    (DELAY * 2, H2, V6_ADDR.into()),
+                    (DELAY * 3, H2, V4_ADDR.into()),
+                ],
+            ),
+            (
+                ProtocolRacing::QuicHeadStart(HEAD_START),
+                vec![
+                    (Duration::ZERO, H3, V6_ADDR.into()),
+                    (HEAD_START, H2, V6_ADDR.into()),
+                    (HEAD_START + DELAY, H3, V4_ADDR.into()),
+                    (HEAD_START * 2 + DELAY, H2, V4_ADDR.into()),
+                ],
+            ),
+            (
+                ProtocolRacing::Parallel,
+                vec![
+                    (Duration::ZERO, H3, V6_ADDR.into()),
+                    (Duration::ZERO, H2, V6_ADDR.into()),
+                    (DELAY, H3, V4_ADDR.into()),
+                    (DELAY, H2, V4_ADDR.into()),
+                ],
+            ),
+            (
+                ProtocolRacing::TcpFirst,
+                vec![
+                    (Duration::ZERO, H2, V6_ADDR.into()),
+                    (DELAY, H2, V4_ADDR.into()),
+                    (DELAY * 2, H3, V6_ADDR.into()),
+                    (DELAY * 3, H3, V4_ADDR.into()),
+                ],
+            ),
+        ];
+
+        for (protocol_racing, expected) in test_cases {
+            let (now, mut he) = setup_with_config(NetworkConfig {
+                protocol_racing: protocol_racing.clone(),
+                ..NetworkConfig::default()
+            });
+
+            he.expect(
+                vec![
+                    (None, Some(out_send_dns_https(Id::from(0)))),
+                    (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                    (None, Some(out_send_dns_a(Id::from(2)))),
+                    (
+                        Some(in_dns_https_positive(Id::from(0))),
+                        Some(out_resolution_delay()),
+                    ),
+                    (
+                        Some(in_dns_a_positive(Id::from(2))),
+                        Some(out_resolution_delay()),
+                    ),
+                ],
+                now,
+            );
+            he.process_input(in_dns_aaaa_positive(Id::from(1)), now);
+
+            assert_eq!(
+                attempt_schedule(&mut he, now),
+                expected,
+                "{protocol_racing:?}"
+            );
+        }
+    }
+
+    /// With parallel racing, QUIC and TCP attempts to the same address run at
+    /// once. The loser is canceled by its id, not by the shared address.
+    #[test]
+    fn parallel_quic_wins() {
+        let (now, mut he) = setup_with_config(NetworkConfig {
+            protocol_racing: ProtocolRacing::Parallel,
+            ..NetworkConfig::default()
+        });
+
+        he.expect(
+            vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
+                (
+                    Some(in_dns_https_positive(Id::from(0))),
+                    Some(out_resolution_delay()),
+                ),
+                (
+                    Some(in_dns_a_positive(Id::from(2))),
+                    Some(out_resolution_delay()),
+                ),
+                (
+                    Some(in_dns_aaaa_positive(Id::from(1))),
+                    Some(out_attempt_v6_h3(Id::from(3))),
+                ),
+                (None, Some(out_attempt_v6_h2(Id::from(4)))),
                 (
                     Some(in_connection_result_positive(Id::from(3))),
-                    Some(Output::Succeeded),
+                    Some(Output::CancelConnection(Id::from(4))),
                 ),
-                // After succeeded, continue to emit Succeeded
                 (None, Some(Output::Succeeded)),
+            ],
+            now,
+        );
+    }
+
+    /// Failing QUIC attempts hint at UDP being blocked, thus demote the
+    /// remaining QUIC endpoints behind TCP.
+    #[test]
+    fn demote_quic_after_failures() {
+        let test_cases = vec![
+            (
+                Some(2),
+                Output::AttemptConnection {
+                    id: Id::from(5),
+                    endpoint: endpoint(V6_ADDR.into(), ConnectionAttemptHttpVersions::H2),
+                },
+            ),
+            (
+                None,
+                Output::AttemptConnection {
+                    id: Id::from(5),
+                    endpoint: endpoint(V6_ADDR_2.into(), ConnectionAttemptHttpVersions::H3),
+                },
+            ),
+        ];
+
+        for (quic_demotion_threshold, expected) in test_cases {
+            let (now, mut he) = setup_with_config(NetworkConfig {
+                quic_demotion_threshold,
+                ..NetworkConfig::default()
+            });
+
+            he.expect(
+                vec![
+                    (None, Some(out_send_dns_https(Id::from(0)))),
+                    (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                    (None, Some(out_send_dns_a(Id::from(2)))),
+                    (
+                        Some(in_dns_https_positive(Id::from(0))),
+                        Some(out_resolution_delay()),
+                    ),
+                    (
+                        Some(in_dns_a_positive(Id::from(2))),
+                        Some(out_resolution_delay()),
+                    ),
+                    (
+                        Some(Input::DnsResult {
+                            id: Id::from(1),
+                            result: DnsResult::Aaaa(Ok(vec![V6_ADDR, V6_ADDR_2])),
+                        }),
+                        Some(out_attempt_v6_h3(Id::from(3))),
+                    ),
+                    (
+                        Some(in_connection_result_negative(Id::from(3))),
+                        Some(out_attempt_v4_h3(Id::from(4))),
+                    ),
+                    (
+                        Some(in_connection_result_negative(Id::from(4))),
+                        Some(expected),
+                    ),
+                ],
+                now,
+            );
+            assert_eq!(
+                he.findings().quic_demoted,
+                quic_demotion_threshold.is_some()
+            );
+        }
+    }
+
+    /// QUIC attempts canceled because TCP won are no sign of UDP being
+    /// blocked.
+    #[test]
+    fn no_quic_demotion_on_canceled_attempts() {
+        let (mut now, mut he) = setup_with_config(NetworkConfig {
+            quic_demotion_threshold: Some(1),
+            ..NetworkConfig::default()
+        });
+
+        he.expect(
+            vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
+                (
+                    Some(in_dns_https_positive_h2_h3(Id::from(0))),
+                    Some(out_resolution_delay()),
+                ),
+                (
+                    Some(in_dns_aaaa_positive(Id::from(1))),
+                    Some(out_attempt_v6_h3(Id::from(3))),
+                ),
+            ],
+            now,
+        );
+
+        now += CONNECTION_ATTEMPT_DELAY;
+        he.expect(
+            vec![
+                (None, Some(out_attempt_v6_h2(Id::from(4)))),
+                (
+                    Some(in_connection_result_positive(Id::from(4))),
+                    Some(Output::CancelConnection(Id::from(3))),
+                ),
                 (None, Some(Output::Succeeded)),
             ],
             now,
         );
+        assert!(!he.findings().quic_demoted);
     }
 
+    /// Failing IPv6 connection attempts, while IPv4 connection attempts do
+    /// not fail, suggest an IPv6 blackhole, in which case the waiting IPv4
+    /// endpoints are attempted next.
     #[test]
-    fn all_connections_failed() {
-        let (now, mut he) = setup();
+    fn promote_ipv4_on_ipv6_blackhole() {
+        let test_cases: Vec<(Option<usize>, bool, Vec<IpAddr>, bool)> = vec![
+            (
+                Some(2),
+                false,
+                vec![
+                    V6_ADDR.into(),
+                    V6_ADDR_2.into(),
+                    V4_ADDR.into(),
+                    V4_ADDR_2.into(),
+                    V6_ADDR_3.into(),
+                ],
+                true,
+            ),
+            // Off by default.
+            (
+                None,
+                false,
+                vec![
+                    V6_ADDR.into(),
+                    V6_ADDR_2.into(),
+                    V6_ADDR_3.into(),
+                    V4_ADDR.into(),
+                    V4_ADDR_2.into(),
+                ],
+                false,
+            ),
+            // IPv4 is just as unresponsive.
+            (
+                Some(2),
+                true,
+                vec![
+                    V6_ADDR.into(),
+                    V4_ADDR.into(),
+                    V6_ADDR_2.into(),
+                    V4_ADDR_2.into(),
+                    V6_ADDR_3.into(),
+                ],
+                false,
+            ),
+        ];
+
+        for (ipv6_blackhole_threshold, ipv4_fails, expected, blackhole) in test_cases {
+            let (mut now, mut he) = setup_with_config(NetworkConfig {
+                first_address_family_count: if ipv4_fails { 1 } else { 3 },
+                ipv6_blackhole_threshold,
+                ..NetworkConfig::default()
+            });
+
+            he.expect(
+                vec![
+                    (None, Some(out_send_dns_https(Id::from(0)))),
+                    (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                    (None, Some(out_send_dns_a(Id::from(2)))),
+                    (
+                        Some(in_dns_https_negative(Id::from(0))),
+                        Some(out_resolution_delay()),
+                    ),
+                    (
+                        Some(Input::DnsResult {
+                            id: Id::from(2),
+                            result: DnsResult::A(Ok(vec![V4_ADDR, V4_ADDR_2])),
+                        }),
+                        Some(out_resolution_delay()),
+                    ),
+                ],
+                now,
+            );
+            he.process_input(
+                Input::DnsResult {
+                    id: Id::from(1),
+                    result: DnsResult::Aaaa(Ok(vec![V6_ADDR, V6_ADDR_2, V6_ADDR_3])),
+                },
+                now,
+            );
+
+            let mut attempted = vec![];
+            while let Some(Output::AttemptConnection { id, endpoint }) = he.process_output(now) {
+                attempted.push(endpoint.address.ip());
+                if endpoint.address.is_ipv6() || ipv4_fails {
+                    he.process_input(in_connection_result_negative(id), now);
+                } else {
+                    now += CONNECTION_ATTEMPT_DELAY;
+                }
+            }
+            assert_eq!(attempted, expected);
+            assert_eq!(he.findings().ipv6_blackhole, blackhole);
+        }
+    }
+
+    /// IPv6 connection attempts merely slower than the connection attempt
+    /// delay are no evidence of an IPv6 blackhole.
+    #[test]
+    fn no_ipv6_blackhole_on_slow_ipv6() {
+        let (mut now, mut he) = setup_with_config(NetworkConfig {
+            ipv6_blackhole_threshold: Some(1),
+            ..NetworkConfig::default()
+        });
+
+        assert_eq!(
+            super::section_5_sorting::first_attempt(&mut he, now),
+            Some(out_attempt_v6_h1_h2(Id::from(3)))
+        );
+        now += CONNECTION_ATTEMPT_DELAY;
+        he.expect(vec![(None, Some(out_attempt_v4_h1_h2(Id::from(4))))], now);
+        assert!(!he.findings().ipv6_blackhole);
+    }
+
+    /// IPv6 connection attempts left unanswered for
+    /// `Timing::ipv6_blackhole_timeout` suggest an IPv6 blackhole, long
+    /// before they time out.
+    #[test]
+    fn promote_ipv4_on_unanswered_ipv6() {
+        let ipv6_addrs = (1..=12)
+            .map(|i| Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i))
+            .collect::<Vec<_>>();
+
+        for (ipv6_blackhole_threshold, ipv6_attempts, blackhole) in
+            [(Some(2), 9, true), (None, 12, false)]
+        {
+            let (mut now, mut he) = setup_with_config(NetworkConfig {
+                first_address_family_count: ipv6_addrs.len(),
+                ipv6_blackhole_threshold,
+                max_concurrent_attempts: None,
+                ..NetworkConfig::default()
+            });
+
+            he.expect(
+                vec![
+                    (None, Some(out_send_dns_https(Id::from(0)))),
+                    (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                    (None, Some(out_send_dns_a(Id::from(2)))),
+                    (
+                        Some(in_dns_https_negative(Id::from(0))),
+                        Some(out_resolution_delay()),
+                    ),
+                    (
+                        Some(Input::DnsResult {
+                            id: Id::from(2),
+                            result: DnsResult::A(Ok(vec![V4_ADDR])),
+                        }),
+                        Some(out_resolution_delay()),
+                    ),
+                ],
+                now,
+            );
+            he.process_input(
+                Input::DnsResult {
+                    id: Id::from(1),
+                    result: DnsResult::Aaaa(Ok(ipv6_addrs.clone())),
+                },
+                now,
+            );
+
+            // No connection attempt ever concludes, time passes only.
+            let mut attempted = vec![];
+            while let Some(output) = he.process_output(now) {
+                match output {
+                    Output::AttemptConnection { endpoint, .. } => {
+                        attempted.push(endpoint.address.ip());
+                        if endpoint.address.is_ipv4() {
+                            break;
+                        }
+                    }
+                    Output::Timer { duration } => now += duration,
+                    output => panic!("unexpected {output:?}"),
+                }
+            }
+            assert_eq!(attempted.len(), ipv6_attempts + 1);
+            assert_eq!(attempted.last(), Some(&IpAddr::from(V4_ADDR)));
+            assert_eq!(he.findings().ipv6_blackhole, blackhole);
+        }
+    }
+
+    #[test]
+    fn never_try_same_attempt_twice() {
+        let (mut now, mut he) = setup();
 
         he.expect(
             vec![
@@ -939,34 +1972,1624 @@ mod section_6_connection_attempts {
                 (None, Some(out_send_dns_aaaa(Id::from(1)))),
                 (None, Some(out_send_dns_a(Id::from(2)))),
                 (
-                    Some(in_dns_https_positive_no_alpn(Id::from(0))),
+                    Some(in_dns_https_negative(Id::from(0))),
+                    Some(out_resolution_delay()),
+                ),
+                (
+                    Some(in_dns_a_negative(Id::from(2))),
                     Some(out_resolution_delay()),
                 ),
                 (
                     Some(in_dns_aaaa_positive(Id::from(1))),
                     Some(out_attempt_v6_h1_h2(Id::from(3))),
                 ),
+            ],
+            now,
+        );
+
+        now += CONNECTION_ATTEMPT_DELAY;
+
+        he.expect(vec![(None, None)], now);
+    }
+
+    #[test]
+    fn successful_connection_cancels_others() {
+        let (mut now, mut he) = setup();
+
+        he.expect(
+            vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
+                (
+                    Some(in_dns_https_positive_no_alpn(Id::from(0))),
+                    Some(out_resolution_delay()),
+                ),
+                (
+                    Some(Input::DnsResult {
+                        id: Id::from(1),
+                        result: DnsResult::Aaaa(Ok(vec![V6_ADDR, V6_ADDR_2])),
+                    }),
+                    Some(out_attempt_v6_h1_h2(Id::from(3))),
+                ),
                 (
                     Some(in_dns_a_positive(Id::from(2))),
                     Some(out_connection_attempt_delay()),
                 ),
+            ],
+            now,
+        );
+
+        now += CONNECTION_ATTEMPT_DELAY;
+        he.expect(vec![(None, Some(out_attempt_v4_h1_h2(Id::from(4))))], now);
+
+        now += CONNECTION_ATTEMPT_DELAY;
+        he.expect(
+            vec![(
+                None,
+                Some(Output::AttemptConnection {
+                    id: Id::from(5),
+                    endpoint: endpoint(V6_ADDR_2.into(), ConnectionAttemptHttpVersions::H2OrH1),
+                }),
+            )],
+            now,
+        );
+        he.expect(
+            vec![
                 (
-                    Some(in_connection_result_negative(Id::from(3))),
-                    Some(out_attempt_v4_h1_h2(Id::from(4))),
-                ),
-                (
-                    Some(in_connection_result_negative(Id::from(4))),
-                    Some(Output::Failed),
+                    Some(in_connection_result_positive(Id::from(3))),
+                    Some(Output::CancelConnection(Id::from(4))),
                 ),
+                (None, Some(Output::CancelConnection(Id::from(5)))),
+                (None, Some(Output::Succeeded)),
             ],
             now,
         );
     }
+
+    #[test]
+    fn failed_connection_tries_next_immediately() {
+        let (now, mut he) = setup();
+
+        he.expect(
+            vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
+                (
+                    Some(in_dns_https_positive_no_alpn(Id::from(0))),
+                    Some(out_resolution_delay()),
+                ),
+                (
+                    Some(in_dns_aaaa_positive(Id::from(1))),
+                    Some(out_attempt_v6_h1_h2(Id::from(3))),
+                ),
+                (
+                    Some(in_dns_a_positive(Id::from(2))),
+                    Some(out_connection_attempt_delay()),
+                ),
+            ],
+            now,
+        );
+
+        he.expect(
+            vec![(
+                Some(in_connection_result_negative(Id::from(3))),
+                Some(out_attempt_v4_h1_h2(Id::from(4))),
+            )],
+            now,
+        );
+    }
+
+    #[test]
+    fn successful_connection_emits_succeeded() {
+        let (now, mut he) = setup();
+
+        he.expect(
+            vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
+                (
+                    Some(in_dns_https_positive_no_alpn(Id::from(0))),
+                    Some(out_resolution_delay()),
+                ),
+                (
+                    Some(in_dns_aaaa_positive(Id::from(1))),
+                    Some(out_attempt_v6_h1_h2(Id::from(3))),
+                ),
+                (
+                    Some(in_connection_result_positive(Id::from(3))),
+                    Some(Output::Succeeded),
+                ),
+            ],
+            now,
+        );
+    }
+
+    #[test]
+    fn succeeded_emitted_once() {
+        let (now, mut he) = setup();
+
+        he.expect(
+            vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
+                (
+                    Some(in_dns_https_positive_no_alpn(Id::from(0))),
+                    Some(out_resolution_delay()),
+                ),
+                (
+                    Some(in_dns_aaaa_positive(Id::from(1))),
+                    Some(out_attempt_v6_h1_h2(Id::from(3))),
+                ),
+                (
+                    Some(in_connection_result_positive(Id::from(3))),
+                    Some(Output::Succeeded),
+                ),
+                // After succeeded, the race is finished.
+                (None, None),
+                (None, None),
+            ],
+            now,
+        );
+        assert!(he.is_finished());
+        assert_eq!(he.outcome(), Some(Outcome::Succeeded));
+    }
+
+    #[test]
+    fn all_connections_failed() {
+        let (now, mut he) = setup();
+
+        he.expect(
+            vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
+                (
+                    Some(in_dns_https_positive_no_alpn(Id::from(0))),
+                    Some(out_resolution_delay()),
+                ),
+                (
+                    Some(in_dns_aaaa_positive(Id::from(1))),
+                    Some(out_attempt_v6_h1_h2(Id::from(3))),
+                ),
+                (
+                    Some(in_dns_a_positive(Id::from(2))),
+                    Some(out_connection_attempt_delay()),
+                ),
+                (
+                    Some(in_connection_result_negative(Id::from(3))),
+                    Some(out_attempt_v4_h1_h2(Id::from(4))),
+                ),
+                (
+                    Some(in_connection_result_negative(Id::from(4))),
+                    Some(Output::Failed(FailureReason::Exhausted)),
+                ),
+            ],
+            now,
+        );
+    }
+}
+
+#[test]
+fn ipv6_blackhole() {
+    let (mut now, mut he) = setup();
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_https_positive(Id::from(0))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_a_positive(Id::from(2))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(out_attempt_v6_h3(Id::from(3))),
+            ),
+        ],
+        now,
+    );
+
+    for _ in 0..42 {
+        now += CONNECTION_ATTEMPT_DELAY;
+        let connection_attempt = he.process_output(now).unwrap().attempt().unwrap();
+        if connection_attempt.address.is_ipv4() {
+            return;
+        }
+    }
+
+    panic!("Did not fall back to IPv4.");
+}
+
+#[test]
+fn ip_host() {
+    let now = Instant::now();
+    let mut he = HappyEyeballs::new("[2001:0DB8::1]", PORT).unwrap();
+
+    he.expect(
+        vec![(
+            None,
+            Some(Output::AttemptConnection {
+                id: Id::from(0),
+                endpoint: Endpoint {
+                    origin_host: "2001:db8::1".to_string(),
+                    target_name: "2001:db8::1".into(),
+                    provenance: Provenance {
+                        source: EndpointSource::IpLiteral,
+                        service_priority: None,
+                    },
+                    ..endpoint(V6_ADDR.into(), ConnectionAttemptHttpVersions::H2OrH1)
+                },
+            }),
+        )],
+        now,
+    );
+}
+
+#[test]
+fn ip_host_never_try_same_attempt_twice() {
+    let mut now = Instant::now();
+    let mut he = HappyEyeballs::new("192.0.2.1", PORT).unwrap();
+
+    he.expect(
+        vec![(
+            None,
+            Some(Output::AttemptConnection {
+                id: Id::from(0),
+                endpoint: Endpoint {
+                    origin_host: "192.0.2.1".to_string(),
+                    target_name: "192.0.2.1".into(),
+                    provenance: Provenance {
+                        source: EndpointSource::IpLiteral,
+                        service_priority: None,
+                    },
+                    ..endpoint(V4_ADDR.into(), ConnectionAttemptHttpVersions::H2OrH1)
+                },
+            }),
+        )],
+        now,
+    );
+
+    for _ in 0..3 {
+        now += CONNECTION_ATTEMPT_DELAY;
+        he.expect(vec![(None, None)], now);
+    }
+
+    he.expect(
+        vec![(
+            Some(in_connection_result_negative(Id::from(0))),
+            Some(Output::Failed(FailureReason::Exhausted)),
+        )],
+        now,
+    );
+}
+
+#[test]
+fn ipv4_only() {
+    let (now, mut he) = setup_with_config(NetworkConfig {
+        ip: IpPreference::Ipv4Only,
+        ..NetworkConfig::default()
+    });
+
+    // No AAAA query, and no IPv6 address hints.
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_a(Id::from(1)))),
+            (None, None),
+            (
+                Some(Input::DnsResult {
+                    id: Id::from(0),
+                    result: DnsResult::Https(Ok(vec![happy_eyeballs::ServiceInfo {
+                        priority: 1,
+                        target_name: HOSTNAME.into(),
+                        alpn_protocols: HashSet::from([HttpVersion::H2]),
+                        ipv6_hints: vec![V6_ADDR],
+                        ipv4_hints: vec![],
+                        ech_config: None,
+                    }])),
+                }),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_a_positive(Id::from(1))),
+                Some(out_attempt_v4_h2(Id::from(2))),
+            ),
+            (
+                Some(in_connection_result_negative(Id::from(2))),
+                Some(Output::Failed(FailureReason::Exhausted)),
+            ),
+        ],
+        now,
+    );
+}
+
+#[test]
+fn ipv6_only() {
+    let (now, mut he) = setup_with_config(NetworkConfig {
+        ip: IpPreference::Ipv6Only,
+        ..NetworkConfig::default()
+    });
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, None),
+        ],
+        now,
+    );
+}
+
+#[test]
+fn ip_host_of_disabled_address_family() {
+    let now = Instant::now();
+    for (host, ip) in [
+        ("[2001:db8::1]", IpPreference::Ipv4Only),
+        ("192.0.2.1", IpPreference::Ipv6Only),
+    ] {
+        let mut he = HappyEyeballs::new_with_network_config(
+            host,
+            PORT,
+            NetworkConfig {
+                ip,
+                ..NetworkConfig::default()
+            },
+        )
+        .unwrap();
+        he.expect(
+            vec![(
+                None,
+                Some(Output::Failed(FailureReason::AddressFamilyDisabled)),
+            )],
+            now,
+        );
+    }
+}
+
+#[test]
+fn not_url_but_ip() {
+    // Neither of these are a valid URL, but they are valid IP addresses.
+    HappyEyeballs::new("::1", PORT).unwrap();
+    HappyEyeballs::new("127.0.0.1", PORT).unwrap();
+}
+
+#[test]
+fn alt_svc_construction() {
+    let now = Instant::now();
+    let config = NetworkConfig {
+        http_versions: HttpVersions::default(),
+        ip: IpPreference::DualStackPreferV6,
+        alt_svc: vec![AltSvc {
+            host: None,
+            port: None,
+            protocol: HttpVersion::H3,
+            expires: None,
+            persist: false,
+        }],
+        ..NetworkConfig::default()
+    };
+    let mut he = HappyEyeballs::new_with_network_config(HOSTNAME, PORT, config).unwrap();
+
+    // Should still send DNS queries as normal
+    he.expect(vec![(None, Some(out_send_dns_https(Id::from(0))))], now);
+}
+
+#[test]
+fn ech_config_propagated_to_endpoint() {
+    let (now, mut he) = setup();
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_aaaa_negative(Id::from(1))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_a_negative(Id::from(2))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(Input::DnsResult {
+                    id: Id::from(0),
+                    result: DnsResult::Https(Ok(vec![happy_eyeballs::ServiceInfo {
+                        priority: 1,
+                        target_name: HOSTNAME.into(),
+                        alpn_protocols: HashSet::from([HttpVersion::H3, HttpVersion::H2]),
+                        ipv6_hints: vec![V6_ADDR],
+                        ipv4_hints: vec![],
+                        ech_config: Some(ECH_CONFIG.to_vec()),
+                    }])),
+                }),
+                Some(Output::AttemptConnection {
+                    id: Id::from(3),
+                    endpoint: Endpoint {
+                        ech_config: Some(ECH_CONFIG.to_vec()),
+                        provenance: HINT,
+                        ..endpoint(V6_ADDR.into(), ConnectionAttemptHttpVersions::H3)
+                    },
+                }),
+            ),
+        ],
+        now,
+    );
+}
+
+#[test]
+fn ech_config_per_service() {
+    let (mut now, mut he) = setup();
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(Input::DnsResult {
+                    id: Id::from(0),
+                    result: DnsResult::Https(Ok(vec![
+                        happy_eyeballs::ServiceInfo {
+                            priority: 1,
+                            target_name: "svc1.example.com.".into(),
+                            alpn_protocols: HashSet::from([HttpVersion::H3, HttpVersion::H2]),
+                            ipv6_hints: vec![],
+                            ipv4_hints: vec![],
+                            ech_config: Some(ECH_CONFIG.to_vec()),
+                        },
+                        happy_eyeballs::ServiceInfo {
+                            priority: 2,
+                            target_name: HOSTNAME.into(),
+                            alpn_protocols: HashSet::from([HttpVersion::H3, HttpVersion::H2]),
+                            ipv6_hints: vec![],
+                            ipv4_hints: vec![],
+                            ech_config: None,
+                        },
+                    ])),
+                }),
+                Some(out_send_dns_svc1(Id::from(3))),
+            ),
+            (
+                None,
+                Some(Output::SendDnsQuery {
+                    id: Id::from(4),
+                    hostname: "svc1.example.com.".into(),
+                    record_type: DnsRecordType::A,
+                }),
+            ),
+        ],
+        now,
+    );
+    he.process_input(
+        Input::DnsResult {
+            id: Id::from(3),
+            result: DnsResult::Aaaa(Ok(vec![V6_ADDR_3])),
+        },
+        now,
+    );
+    he.process_input(in_dns_a_negative(Id::from(4)), now);
+    he.process_input(in_dns_aaaa_positive(Id::from(1)), now);
+    he.process_input(in_dns_a_negative(Id::from(2)), now);
+
+    let mut attempted = vec![];
+    while let Some(output) = he.process_output(now) {
+        if let Output::AttemptConnection { endpoint, .. } = output {
+            attempted.push(endpoint);
+        }
+        now += CONNECTION_ATTEMPT_DELAY;
+    }
+    let ech_config = |ip: Ipv6Addr| {
+        attempted
+            .iter()
+            .find(|e| e.address.ip() == ip)
+            .map(|e| e.ech_config.clone())
+            .unwrap()
+    };
+    assert_eq!(ech_config(V6_ADDR_3), Some(ECH_CONFIG.to_vec()));
+    assert_eq!(ech_config(V6_ADDR), None);
+}
+
+#[test]
+fn ech_required() {
+    let (mut now, mut he) = setup_with_config(NetworkConfig {
+        ech: EchPolicy::Required,
+        ..NetworkConfig::default()
+    });
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+        ],
+        now,
+    );
+    he.process_input(in_dns_aaaa_positive(Id::from(1)), now);
+    he.process_input(in_dns_a_positive(Id::from(2)), now);
+
+    // No attempt before knowing whether ECH is advertised.
+    now += RESOLUTION_DELAY;
+    assert!(!matches!(
+        he.process_output(now),
+        Some(Output::AttemptConnection { .. })
+    ));
+
+    // The origin itself does not advertise ECH, "svc1.example.com." does.
+    he.process_input(
+        Input::DnsResult {
+            id: Id::from(0),
+            result: DnsResult::Https(Ok(vec![
+                happy_eyeballs::ServiceInfo {
+                    priority: 1,
+                    target_name: "svc1.example.com.".into(),
+                    alpn_protocols: HashSet::from([HttpVersion::H3, HttpVersion::H2]),
+                    ipv6_hints: vec![V6_ADDR_2],
+                    ipv4_hints: vec![],
+                    ech_config: Some(ECH_CONFIG.to_vec()),
+                },
+                happy_eyeballs::ServiceInfo {
+                    priority: 2,
+                    target_name: HOSTNAME.into(),
+                    alpn_protocols: HashSet::from([HttpVersion::H3, HttpVersion::H2]),
+                    ipv6_hints: vec![],
+                    ipv4_hints: vec![],
+                    ech_config: None,
+                },
+            ])),
+        },
+        now,
+    );
+
+    let mut attempted = vec![];
+    while let Some(output) = he.process_output(now) {
+        if let Output::AttemptConnection { endpoint, .. } = output {
+            attempted.push(endpoint);
+        }
+        now += CONNECTION_ATTEMPT_DELAY;
+    }
+    assert!(!attempted.is_empty());
+    assert!(
+        attempted
+            .iter()
+            .all(|e| e.ech_config.as_deref() == Some(ECH_CONFIG))
+    );
+}
+
+/// Services advertising ECH next to services without suggest ECH being
+/// stripped on the network, which makes ECH required in future races.
+#[test]
+fn network_state_ech_stripped() {
+    let race = |known_findings: Findings| {
+        let (mut now, mut he) = setup_with_config(NetworkConfig {
+            known_findings,
+            ..NetworkConfig::default()
+        });
+        he.expect(
+            vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
+            ],
+            now,
+        );
+        he.process_input(in_dns_aaaa_positive(Id::from(1)), now);
+        he.process_input(in_dns_a_positive(Id::from(2)), now);
+        he.process_input(
+            Input::DnsResult {
+                id: Id::from(0),
+                result: DnsResult::Https(Ok(vec![
+                    happy_eyeballs::ServiceInfo {
+                        priority: 1,
+                        target_name: "svc1.example.com.".into(),
+                        alpn_protocols: HashSet::from([HttpVersion::H2]),
+                        ipv6_hints: vec![V6_ADDR_2],
+                        ipv4_hints: vec![],
+                        ech_config: Some(ECH_CONFIG.to_vec()),
+                    },
+                    happy_eyeballs::ServiceInfo {
+                        priority: 2,
+                        target_name: HOSTNAME.into(),
+                        alpn_protocols: HashSet::from([HttpVersion::H2]),
+                        ipv6_hints: vec![],
+                        ipv4_hints: vec![],
+                        ech_config: None,
+                    },
+                ])),
+            },
+            now,
+        );
+
+        let mut attempted = vec![];
+        while let Some(output) = he.process_output(now) {
+            if let Output::AttemptConnection { endpoint, .. } = output {
+                attempted.push(endpoint.ech_config.is_some());
+            }
+            now += CONNECTION_ATTEMPT_DELAY;
+        }
+        (attempted, he.findings().clone())
+    };
+
+    let (attempted, findings) = race(Findings::default());
+    assert!(attempted.contains(&false));
+    assert!(findings.ech_stripped);
+
+    let network = NetworkId::from("home-wifi");
+    let wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let mut store = NetworkStateStore::default();
+    store.record(network.clone(), &findings, wall + Duration::from_secs(3600));
+
+    let (attempted, _) = race(store.findings(&network, wall));
+    assert!(!attempted.is_empty());
+    assert!(!attempted.contains(&false));
+}
+
+#[test]
+fn ech_disabled_and_grease() {
+    let https = || Input::DnsResult {
+        id: Id::from(0),
+        result: DnsResult::Https(Ok(vec![happy_eyeballs::ServiceInfo {
+            priority: 1,
+            target_name: HOSTNAME.into(),
+            alpn_protocols: HashSet::from([HttpVersion::H2]),
+            ipv6_hints: vec![],
+            ipv4_hints: vec![],
+            ech_config: Some(ECH_CONFIG.to_vec()),
+        }])),
+    };
+
+    for (ech, ech_grease, expected_ech_config, expected_grease) in [
+        (EchPolicy::Disabled, true, None, false),
+        (
+            EchPolicy::Opportunistic,
+            true,
+            Some(ECH_CONFIG.to_vec()),
+            false,
+        ),
+    ] {
+        let (now, mut he) = setup_with_config(NetworkConfig {
+            ech,
+            ech_grease,
+            ..NetworkConfig::default()
+        });
+        he.expect(
+            vec![
+                (None, Some(out_send_dns_https(Id::from(0)))),
+                (None, Some(out_send_dns_aaaa(Id::from(1)))),
+                (None, Some(out_send_dns_a(Id::from(2)))),
+                (Some(https()), Some(out_resolution_delay())),
+                (
+                    Some(in_dns_aaaa_positive(Id::from(1))),
+                    Some(Output::AttemptConnection {
+                        id: Id::from(3),
+                        endpoint: Endpoint {
+                            ech_config: expected_ech_config,
+                            ech_grease: expected_grease,
+                            ..endpoint(V6_ADDR.into(), ConnectionAttemptHttpVersions::H2)
+                        },
+                    }),
+                ),
+            ],
+            now,
+        );
+    }
+
+    // Without an ECH config, GREASE ECH is sent.
+    let (now, mut he) = setup_with_config(NetworkConfig {
+        ech_grease: true,
+        ..NetworkConfig::default()
+    });
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_https_negative(Id::from(0))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(Output::AttemptConnection {
+                    id: Id::from(3),
+                    endpoint: Endpoint {
+                        ech_grease: true,
+                        ..endpoint(V6_ADDR.into(), ConnectionAttemptHttpVersions::H2OrH1)
+                    },
+                }),
+            ),
+        ],
+        now,
+    );
+}
+
+#[test]
+fn ech_config_from_https_applies_to_aaaa() {
+    let (now, mut he) = setup();
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(Input::DnsResult {
+                    id: Id::from(0),
+                    result: DnsResult::Https(Ok(vec![happy_eyeballs::ServiceInfo {
+                        priority: 1,
+                        target_name: HOSTNAME.into(),
+                        alpn_protocols: HashSet::from([HttpVersion::H3, HttpVersion::H2]),
+                        ipv6_hints: vec![],
+                        ipv4_hints: vec![],
+                        ech_config: Some(ECH_CONFIG.to_vec()),
+                    }])),
+                }),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(Output::AttemptConnection {
+                    id: Id::from(3),
+                    endpoint: Endpoint {
+                        ech_config: Some(ECH_CONFIG.to_vec()),
+                        ..endpoint(V6_ADDR.into(), ConnectionAttemptHttpVersions::H3)
+                    },
+                }),
+            ),
+        ],
+        now,
+    );
+}
+
+#[test]
+fn multiple_target_names() {
+    let (now, mut he) = setup();
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            // HTTPS response with a different target name
+            (
+                Some(in_dns_https_positive_svc1(Id::from(0))),
+                Some(out_send_dns_svc1(Id::from(3))),
+            ),
+            // Now we have queries for both "example.com" and "svc1.example.com."
+            // Getting a positive AAAA for the main host doesn't suppress the
+            // hints of "svc1.example.com.".
+            (
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(Output::AttemptConnection {
+                    id: Id::from(4),
+                    endpoint: Endpoint {
+                        address: SocketAddr::new(V6_ADDR_2.into(), PORT),
+                        target_name: "svc1.example.com.".into(),
+                        provenance: HINT,
+                        ..endpoint(V6_ADDR_2.into(), ConnectionAttemptHttpVersions::H3)
+                    },
+                }),
+            ),
+        ],
+        now,
+    );
+}
+
+#[test]
+fn https_rr_disabled() {
+    let (now, mut he) = setup_with_config(NetworkConfig {
+        https_rr: false,
+        ..NetworkConfig::default()
+    });
+
+    // No HTTPS query and no waiting for SVCB information.
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_aaaa(Id::from(0)))),
+            (None, Some(out_send_dns_a(Id::from(1)))),
+            (
+                Some(in_dns_aaaa_positive(Id::from(0))),
+                Some(out_attempt_v6_h1_h2(Id::from(2))),
+            ),
+        ],
+        now,
+    );
+
+    // Alternative services still add HTTP/3.
+    let (now, mut he) = setup_with_config(NetworkConfig {
+        https_rr: false,
+        alt_svc: vec![AltSvc {
+            host: None,
+            port: None,
+            protocol: HttpVersion::H3,
+            expires: None,
+            persist: false,
+        }],
+        ..NetworkConfig::default()
+    });
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_aaaa(Id::from(0)))),
+            (None, Some(out_send_dns_a(Id::from(1)))),
+            (
+                Some(in_dns_aaaa_positive(Id::from(0))),
+                Some(out_attempt_v6_h3(Id::from(2))),
+            ),
+        ],
+        now,
+    );
+}
+
+#[test]
+fn target_name_limits() {
+    // Many services, listed in reverse order of priority.
+    let https = || Input::DnsResult {
+        id: Id::from(0),
+        result: DnsResult::Https(Ok((1..=8u16)
+            .rev()
+            .map(|priority| happy_eyeballs::ServiceInfo {
+                priority,
+                target_name: format!("svc{priority}.example.com.").as_str().into(),
+                alpn_protocols: HashSet::from([HttpVersion::H2]),
+                ipv6_hints: vec![Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, priority)],
+                ipv4_hints: vec![],
+                ech_config: None,
+            })
+            .collect())),
+    };
+    let race = |limits: Limits| {
+        let (mut now, mut he) = setup_with_config(NetworkConfig {
+            limits,
+            ..NetworkConfig::default()
+        });
+        let mut queried = vec![];
+        let mut attempted = vec![];
+        while let Some(output) = he.process_output(now) {
+            match output {
+                Output::SendDnsQuery {
+                    id,
+                    hostname,
+                    record_type,
+                } => {
+                    // Only the hints carry addresses.
+                    he.process_input(
+                        match record_type {
+                            DnsRecordType::Https => https(),
+                            DnsRecordType::Aaaa => in_dns_aaaa_negative(id),
+                            DnsRecordType::A => in_dns_a_negative(id),
+                        },
+                        now,
+                    );
+                    queried.push((hostname, record_type));
+                }
+                Output::AttemptConnection { endpoint, .. } => attempted.push(endpoint),
+                _ => {}
+            }
+            now += CONNECTION_ATTEMPT_DELAY;
+        }
+        let queried_target_names = queried
+            .iter()
+            .map(|(hostname, _)| hostname.clone())
+            .filter(|hostname| *hostname != HOSTNAME.into())
+            .collect::<Vec<_>>();
+        (queried, queried_target_names, attempted)
+    };
+
+    // Only the services with the highest priority are resolved and their
+    // hints used.
+    let (_, target_names, attempted) = race(Limits {
+        max_target_names: 2,
+        ..Limits::default()
+    });
+    assert_eq!(
+        target_names,
+        [
+            "svc1.example.com.",
+            "svc1.example.com.",
+            "svc2.example.com.",
+            "svc2.example.com."
+        ]
+        .map(TargetName::from)
+    );
+    assert_eq!(attempted.len(), 2);
+    assert!(
+        attempted
+            .iter()
+            .all(|e| target_names.contains(&e.target_name))
+    );
+
+    // The DNS queries are bounded overall.
+    let (queried, _, _) = race(Limits {
+        max_dns_queries: 5,
+        ..Limits::default()
+    });
+    assert_eq!(queried.len(), 5);
+
+    // So are the endpoints, keeping the services with the highest priority.
+    let (_, _, attempted) = race(Limits {
+        max_endpoints: 3,
+        ..Limits::default()
+    });
+    assert_eq!(
+        attempted
+            .iter()
+            .map(|e| e.provenance.service_priority)
+            .collect::<Vec<_>>(),
+        [Some(1), Some(2), Some(3)]
+    );
+}
+
+/// The address hints of services with the origin's TargetName are bounded too,
+/// by `Limits::max_services` and `Limits::max_address_hints`.
+#[test]
+fn service_and_address_hint_limits() {
+    let (mut now, mut he) = setup_with_config(NetworkConfig {
+        limits: Limits {
+            max_services: 2,
+            max_address_hints: 3,
+            max_endpoints: 100,
+            ..Limits::default()
+        },
+        max_concurrent_attempts: None,
+        ..NetworkConfig::default()
+    });
+    // Many services, listed in reverse order of priority, with many hints.
+    let https = Input::DnsResult {
+        id: Id::from(0),
+        result: DnsResult::Https(Ok((1..=8u16)
+            .rev()
+            .map(|priority| happy_eyeballs::ServiceInfo {
+                priority,
+                target_name: ".".into(),
+                alpn_protocols: HashSet::from([HttpVersion::H2]),
+                ipv6_hints: (1..=8)
+                    .map(|i| Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, priority, i))
+                    .collect(),
+                ipv4_hints: vec![],
+                ech_config: None,
+            })
+            .collect())),
+    };
+
+    let mut https = Some(https);
+    let mut attempted = vec![];
+    while let Some(output) = he.process_output(now) {
+        match output {
+            Output::SendDnsQuery {
+                id, record_type, ..
+            } => he.process_input(
+                match record_type {
+                    DnsRecordType::Https => https.take().unwrap(),
+                    DnsRecordType::Aaaa => in_dns_aaaa_negative(id),
+                    DnsRecordType::A => in_dns_a_negative(id),
+                },
+                now,
+            ),
+            Output::AttemptConnection { endpoint, .. } => attempted.push(endpoint.address.ip()),
+            _ => {}
+        }
+        now += CONNECTION_ATTEMPT_DELAY;
+    }
+
+    attempted.sort();
+    assert_eq!(
+        attempted,
+        [(1, 1), (1, 2), (1, 3), (2, 1), (2, 2), (2, 3)].map(|(priority, i)| IpAddr::from(
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, priority, i)
+        ))
+    );
+}
+
+/// Endpoints already attempted count against `Limits::max_endpoints` and
+/// both address families are kept.
+#[test]
+fn max_endpoints_stable_across_answers() {
+    let (mut now, mut he) = setup_with_config(NetworkConfig {
+        limits: Limits {
+            max_endpoints: 2,
+            ..Limits::default()
+        },
+        ..NetworkConfig::default()
+    });
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_https_negative(Id::from(0))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(Input::DnsResult {
+                    id: Id::from(2),
+                    result: DnsResult::A(Ok(vec![V4_ADDR, V4_ADDR_2])),
+                }),
+                Some(out_resolution_delay()),
+            ),
+        ],
+        now,
+    );
+    now += RESOLUTION_DELAY;
+    he.expect(vec![(None, Some(out_attempt_v4_h1_h2(Id::from(3))))], now);
+
+    he.process_input(
+        Input::DnsResult {
+            id: Id::from(1),
+            result: DnsResult::Aaaa(Ok(vec![V6_ADDR, V6_ADDR_2, V6_ADDR_3])),
+        },
+        now,
+    );
+    now += CONNECTION_ATTEMPT_DELAY;
+    let mut attempted = vec![];
+    while let Some(Output::AttemptConnection { endpoint, .. }) = he.process_output(now) {
+        attempted.push(endpoint.address.ip());
+        now += CONNECTION_ATTEMPT_DELAY;
+    }
+    assert_eq!(attempted, [IpAddr::from(V6_ADDR)]);
+}
+
+#[test]
+fn max_concurrent_attempts() {
+    let (mut now, mut he) = setup_with_config(NetworkConfig {
+        max_concurrent_attempts: Some(2),
+        ..NetworkConfig::default()
+    });
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_https_negative(Id::from(0))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_a_negative(Id::from(2))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(Input::DnsResult {
+                    id: Id::from(1),
+                    result: DnsResult::Aaaa(Ok(vec![V6_ADDR, V6_ADDR_2, V6_ADDR_3])),
+                }),
+                Some(out_attempt_v6_h1_h2(Id::from(3))),
+            ),
+            (None, Some(out_connection_attempt_delay())),
+        ],
+        now,
+    );
+    now += CONNECTION_ATTEMPT_DELAY;
+    he.expect(
+        vec![(
+            None,
+            Some(Output::AttemptConnection {
+                id: Id::from(4),
+                endpoint: endpoint(V6_ADDR_2.into(), ConnectionAttemptHttpVersions::H2OrH1),
+            }),
+        )],
+        now,
+    );
+
+    // At the cap, neither an attempt nor a timer.
+    now += CONNECTION_ATTEMPT_DELAY;
+    he.expect(vec![(None, None)], now);
+
+    // A concluded attempt frees a slot.
+    he.expect(
+        vec![(
+            Some(in_connection_result_negative(Id::from(3))),
+            Some(Output::AttemptConnection {
+                id: Id::from(5),
+                endpoint: endpoint(V6_ADDR_3.into(), ConnectionAttemptHttpVersions::H2OrH1),
+            }),
+        )],
+        now,
+    );
+}
+
+#[test]
+fn attempt_budget() {
+    let race = |attempt_budget| {
+        let (mut now, mut he) = setup_with_config(NetworkConfig {
+            attempt_budget: Some(attempt_budget),
+            ..NetworkConfig::default()
+        });
+        let mut attempted = vec![];
+        while let Some(output) = he.process_output(now) {
+            match output {
+                Output::SendDnsQuery {
+                    id, record_type, ..
+                } => he.process_input(
+                    match record_type {
+                        DnsRecordType::Https => in_dns_https_negative(id),
+                        DnsRecordType::Aaaa => Input::DnsResult {
+                            id,
+                            result: DnsResult::Aaaa(Ok(vec![V6_ADDR, V6_ADDR_2, V6_ADDR_3])),
+                        },
+                        DnsRecordType::A => in_dns_a_positive(id),
+                    },
+                    now,
+                ),
+                Output::AttemptConnection { id, endpoint } => {
+                    attempted.push(endpoint.address.ip());
+                    he.process_input(in_connection_result_negative(id), now);
+                }
+                Output::Failed(reason) => return (attempted, reason),
+                _ => {}
+            }
+            now += CONNECTION_ATTEMPT_DELAY;
+        }
+        unreachable!();
+    };
+
+    assert_eq!(
+        race(AttemptBudget::new(2)),
+        (
+            vec![V6_ADDR.into(), V4_ADDR.into()],
+            FailureReason::AttemptBudgetExhausted
+        )
+    );
+
+    // A costly IPv6 attempt leaves room for an IPv4 attempt only.
+    assert_eq!(
+        race(AttemptBudget {
+            ipv6_weight: 3,
+            ..AttemptBudget::new(4)
+        }),
+        (
+            vec![V6_ADDR.into(), V4_ADDR.into()],
+            FailureReason::AttemptBudgetExhausted
+        )
+    );
+
+    // A budget covering all endpoints.
+    assert_eq!(race(AttemptBudget::new(4)).1, FailureReason::Exhausted);
+
+    // Costs saturate rather than overflow.
+    assert_eq!(
+        race(AttemptBudget {
+            ipv6_weight: u32::MAX,
+            ipv4_weight: u32::MAX,
+            quic_weight: u32::MAX,
+            tcp_weight: u32::MAX,
+            ..AttemptBudget::new(u32::MAX)
+        }),
+        (vec![V6_ADDR.into()], FailureReason::AttemptBudgetExhausted)
+    );
+}
+
+#[test]
+fn poll_timeout() {
+    let (start, mut he) = setup();
+    assert_eq!(he.poll_timeout(), None);
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (None, None),
+        ],
+        start,
+    );
+    assert_eq!(he.poll_timeout(), Some(start + DEFAULT_DNS_TIMEOUT));
+
+    he.expect(
+        vec![(
+            Some(in_dns_aaaa_positive(Id::from(1))),
+            Some(out_resolution_delay()),
+        )],
+        start,
+    );
+    assert_eq!(he.poll_timeout(), Some(start + RESOLUTION_DELAY));
+
+    let now = start + RESOLUTION_DELAY;
+    he.handle_timeout(now);
+    he.expect(vec![(None, Some(out_attempt_v6_h1_h2(Id::from(3))))], now);
+
+    // The outstanding DNS queries time out.
+    he.handle_timeout(start + DEFAULT_DNS_TIMEOUT);
+    he.expect(vec![(None, None)], start + DEFAULT_DNS_TIMEOUT);
+    let deadline = now + DEFAULT_CONNECTION_ATTEMPT_TIMEOUT;
+    assert_eq!(he.poll_timeout(), Some(deadline));
+
+    // So does the connection attempt, which is canceled.
+    he.handle_timeout(deadline);
+    he.expect(
+        vec![
+            (None, Some(Output::CancelConnection(Id::from(3)))),
+            (None, Some(Output::Failed(FailureReason::Exhausted))),
+        ],
+        deadline,
+    );
+    assert_eq!(he.poll_timeout(), None);
+}
+
+#[test]
+fn dns_timeout() {
+    let dns_timeout = Duration::from_secs(1);
+    let (start, mut he) = setup_with_config(NetworkConfig {
+        timing: Timing {
+            dns_timeout,
+            ..Timing::default()
+        },
+        ..NetworkConfig::default()
+    });
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (None, None),
+        ],
+        start,
+    );
+    let deadline = start + dns_timeout;
+    assert_eq!(he.poll_timeout(), Some(deadline));
+
+    // Not yet expired.
+    let now = deadline - Duration::from_millis(1);
+    he.handle_timeout(now);
+    he.expect(vec![(None, None)], now);
+    assert_eq!(he.poll_timeout(), Some(deadline));
+
+    // All queries time out, leaving no endpoint.
+    he.handle_timeout(deadline);
+    he.expect(
+        vec![(None, Some(Output::Failed(FailureReason::Exhausted)))],
+        deadline,
+    );
+    assert_eq!(he.poll_timeout(), None);
+}
+
+#[test]
+fn connection_attempt_timeout() {
+    let connection_attempt_timeout = Duration::from_secs(1);
+    let (start, mut he) = setup_with_config(NetworkConfig {
+        timing: Timing {
+            connection_attempt_timeout,
+            ..Timing::default()
+        },
+        ..NetworkConfig::default()
+    });
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_https_negative(Id::from(0))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_a_negative(Id::from(2))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(out_attempt_v6_h1_h2(Id::from(3))),
+            ),
+        ],
+        start,
+    );
+    // Nothing left to attempt after the connection attempt delay.
+    he.handle_timeout(start + CONNECTION_ATTEMPT_DELAY);
+    he.expect(vec![(None, None)], start + CONNECTION_ATTEMPT_DELAY);
+    let deadline = start + connection_attempt_timeout;
+    assert_eq!(he.poll_timeout(), Some(deadline));
+
+    // Not yet expired.
+    let now = deadline - Duration::from_millis(1);
+    he.handle_timeout(now);
+    he.expect(vec![(None, None)], now);
+    assert_eq!(he.poll_timeout(), Some(deadline));
+
+    // Expired, thus canceled.
+    he.handle_timeout(deadline);
+    he.expect(
+        vec![
+            (None, Some(Output::CancelConnection(Id::from(3)))),
+            (None, Some(Output::Failed(FailureReason::Exhausted))),
+        ],
+        deadline,
+    );
+}
+
+#[test]
+fn drain_outputs() {
+    let (now, mut he) = setup();
+
+    assert_eq!(
+        he.drain_outputs(now).collect::<Vec<_>>(),
+        [
+            out_send_dns_https(Id::from(0)),
+            out_send_dns_aaaa(Id::from(1)),
+            out_send_dns_a(Id::from(2)),
+        ]
+    );
+
+    // Ends at a timer, without yielding it.
+    he.process_input(in_dns_https_negative(Id::from(0)), now);
+    assert_eq!(he.drain_outputs(now).count(), 0);
+    assert!(!he.is_finished());
+
+    he.process_input(in_dns_aaaa_negative(Id::from(1)), now);
+    he.process_input(in_dns_a_negative(Id::from(2)), now);
+    assert_eq!(
+        he.drain_outputs(now).collect::<Vec<_>>(),
+        [Output::Failed(FailureReason::Exhausted)]
+    );
+    assert_eq!(
+        he.outcome(),
+        Some(Outcome::Failed(FailureReason::Exhausted))
+    );
+
+    // The terminal output is returned once.
+    assert_eq!(he.drain_outputs(now).count(), 0);
+    assert_eq!(he.process_output(now), None);
+}
+
+#[test]
+fn alt_svc_used_immediately() {
+    let now = Instant::now();
+    let config = NetworkConfig {
+        http_versions: HttpVersions::default(),
+        ip: IpPreference::DualStackPreferV6,
+        alt_svc: vec![AltSvc {
+            host: None,
+            port: None,
+            protocol: HttpVersion::H3,
+            expires: None,
+            persist: false,
+        }],
+        ..NetworkConfig::default()
+    };
+    let mut he = HappyEyeballs::new_with_network_config(HOSTNAME, PORT, config).unwrap();
+
+    // Alt-svc with H3 should make H3 available even without HTTPS DNS response
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_https_negative(Id::from(0))),
+                Some(out_resolution_delay()),
+            ),
+            // Alt-svc provided H3, so we should attempt H3 connection
+            (
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(out_attempt_v6_h3(Id::from(3))),
+            ),
+        ],
+        now,
+    );
+}
+
+/// Website with HTTPS record with `noDefaultAlpn` set.
+///
+/// See e.g. <adamwoodland.com>.
+#[test]
+fn no_default_alpn() {
+    let (now, mut he) = setup();
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_https_positive_h2_h3(Id::from(0))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(out_attempt_v6_h3(Id::from(3))),
+            ),
+            (
+                Some(in_dns_a_positive(Id::from(2))),
+                Some(out_connection_attempt_delay()),
+            ),
+            (
+                Some(in_connection_result_negative(Id::from(3))),
+                Some(out_attempt_v4_h3(Id::from(4))),
+            ),
+            (
+                Some(in_connection_result_negative(Id::from(4))),
+                Some(out_attempt_v6_h2(Id::from(5))),
+            ),
+            (
+                Some(in_connection_result_negative(Id::from(5))),
+                Some(out_attempt_v4_h2(Id::from(6))),
+            ),
+            (
+                Some(in_connection_result_negative(Id::from(6))),
+                Some(Output::Failed(FailureReason::Exhausted)),
+            ),
+        ],
+        now,
+    );
+}
+
+#[test]
+fn broken_alternative_skipped_in_next_race() {
+    let (now, mut he) = setup();
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_https_positive_h2_h3(Id::from(0))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(out_attempt_v6_h3(Id::from(3))),
+            ),
+            (
+                Some(in_dns_a_positive(Id::from(2))),
+                Some(out_connection_attempt_delay()),
+            ),
+            (
+                Some(in_connection_result_negative(Id::from(3))),
+                Some(out_attempt_v4_h3(Id::from(4))),
+            ),
+            (
+                Some(in_connection_result_negative(Id::from(4))),
+                Some(out_attempt_v6_h2(Id::from(5))),
+            ),
+            (
+                Some(in_connection_result_positive(Id::from(5))),
+                Some(Output::Succeeded),
+            ),
+        ],
+        now,
+    );
+    assert_eq!(he.broken_alternatives(), HashSet::from([HttpVersion::H3]));
+
+    let mut broken_alternatives = BrokenAlternatives::default();
+    for protocol in he.broken_alternatives() {
+        broken_alternatives.mark_broken(he.origin(), protocol, now);
+    }
+
+    let (now, mut he) = setup_with_config(NetworkConfig {
+        broken_alternatives: broken_alternatives.broken_protocols(&he.origin(), now),
+        ..NetworkConfig::default()
+    });
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_https_positive_h2_h3(Id::from(0))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(out_attempt_v6_h2(Id::from(3))),
+            ),
+        ],
+        now,
+    );
+}
+
+/// A QUIC connection attempt canceled because TCP won does not count as
+/// failed.
+#[test]
+fn no_broken_alternative_when_quic_canceled() {
+    let (mut now, mut he) = setup();
+
+    he.expect(
+        vec![
+            (None, Some(out_send_dns_https(Id::from(0)))),
+            (None, Some(out_send_dns_aaaa(Id::from(1)))),
+            (None, Some(out_send_dns_a(Id::from(2)))),
+            (
+                Some(in_dns_https_positive_h2_h3(Id::from(0))),
+                Some(out_resolution_delay()),
+            ),
+            (
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(out_attempt_v6_h3(Id::from(3))),
+            ),
+            (
+                Some(in_dns_a_positive(Id::from(2))),
+                Some(out_connection_attempt_delay()),
+            ),
+            (
+                Some(in_connection_result_negative(Id::from(3))),
+                Some(out_attempt_v4_h3(Id::from(4))),
+            ),
+        ],
+        now,
+    );
+
+    now += CONNECTION_ATTEMPT_DELAY;
+    he.expect(
+        vec![
+            (None, Some(out_attempt_v6_h2(Id::from(5)))),
+            (
+                Some(in_connection_result_positive(Id::from(5))),
+                Some(Output::CancelConnection(Id::from(4))),
+            ),
+            (None, Some(Output::Succeeded)),
+        ],
+        now,
+    );
+    assert_eq!(he.broken_alternatives(), HashSet::new());
+}
+
+#[test]
+fn broken_alternative_exponential_backoff() {
+    let mut now = Instant::now();
+    let origin = Origin {
+        scheme: "https".to_string(),
+        host: HOSTNAME.to_string(),
+        port: PORT,
+    };
+    let mut broken_alternatives = BrokenAlternatives::default();
+
+    let mut delay = BROKEN_ALTERNATIVE_INITIAL_DELAY;
+    for _ in 0..3 {
+        broken_alternatives.mark_broken(origin.clone(), HttpVersion::H3, now);
+        assert!(broken_alternatives.is_broken(&origin, HttpVersion::H3, now + delay / 2));
+        assert!(!broken_alternatives.is_broken(&origin, HttpVersion::H3, now + delay));
+        assert!(!broken_alternatives.is_broken(&origin, HttpVersion::H2, now));
+        now += delay;
+        delay *= 2;
+    }
+
+    for _ in 0..20 {
+        broken_alternatives.mark_broken(origin.clone(), HttpVersion::H3, now);
+    }
+    assert!(!broken_alternatives.is_broken(
+        &origin,
+        HttpVersion::H3,
+        now + BROKEN_ALTERNATIVE_MAX_DELAY
+    ));
+
+    broken_alternatives.confirm(&origin, HttpVersion::H3);
+    assert!(!broken_alternatives.is_broken(&origin, HttpVersion::H3, now));
+    broken_alternatives.mark_broken(origin.clone(), HttpVersion::H3, now);
+    assert!(!broken_alternatives.is_broken(
+        &origin,
+        HttpVersion::H3,
+        now + BROKEN_ALTERNATIVE_INITIAL_DELAY
+    ));
 }
 
 #[test]
-fn ipv6_blackhole() {
-    let (mut now, mut he) = setup();
+fn alt_svc_elsewhere() {
+    const ALT_HOST: &str = "alt.example.net";
+    const ALT_PORT: u16 = 8443;
+
+    let (now, mut he) = setup_with_config(NetworkConfig {
+        alt_svc: vec![AltSvc {
+            host: Some(ALT_HOST.to_string()),
+            port: Some(ALT_PORT),
+            protocol: HttpVersion::H3,
+            expires: None,
+            persist: false,
+        }],
+        ..NetworkConfig::default()
+    });
 
     he.expect(
         vec![
@@ -974,68 +3597,162 @@ fn ipv6_blackhole() {
             (None, Some(out_send_dns_aaaa(Id::from(1)))),
             (None, Some(out_send_dns_a(Id::from(2)))),
             (
-                Some(in_dns_https_positive(Id::from(0))),
+                None,
+                Some(Output::SendDnsQuery {
+                    id: Id::from(3),
+                    hostname: ALT_HOST.into(),
+                    record_type: DnsRecordType::Aaaa,
+                }),
+            ),
+            (
+                None,
+                Some(Output::SendDnsQuery {
+                    id: Id::from(4),
+                    hostname: ALT_HOST.into(),
+                    record_type: DnsRecordType::A,
+                }),
+            ),
+            (
+                Some(in_dns_https_negative(Id::from(0))),
                 Some(out_resolution_delay()),
             ),
             (
                 Some(in_dns_a_positive(Id::from(2))),
                 Some(out_resolution_delay()),
             ),
+            (
+                Some(Input::DnsResult {
+                    id: Id::from(3),
+                    result: DnsResult::Aaaa(Ok(vec![V6_ADDR_2])),
+                }),
+                Some(out_resolution_delay()),
+            ),
             (
                 Some(in_dns_aaaa_positive(Id::from(1))),
-                Some(out_attempt_v6_h3(Id::from(3))),
+                Some(Output::AttemptConnection {
+                    id: Id::from(5),
+                    endpoint: Endpoint {
+                        address: SocketAddr::new(V6_ADDR_2.into(), ALT_PORT),
+                        target_name: ALT_HOST.into(),
+                        provenance: Provenance {
+                            source: EndpointSource::AltSvc,
+                            service_priority: None,
+                        },
+                        ..endpoint(V6_ADDR_2.into(), ConnectionAttemptHttpVersions::H3)
+                    },
+                }),
             ),
         ],
         now,
     );
-
-    for _ in 0..42 {
-        now += CONNECTION_ATTEMPT_DELAY;
-        let connection_attempt = he.process_output(now).unwrap().attempt().unwrap();
-        if connection_attempt.address.is_ipv4() {
-            return;
-        }
-    }
-
-    panic!("Did not fall back to IPv4.");
 }
 
 #[test]
-fn ip_host() {
+fn alt_svc_header() {
     let now = Instant::now();
-    let mut he = HappyEyeballs::new("[2001:0DB8::1]", PORT).unwrap();
 
-    he.expect(vec![(None, Some(out_attempt_v6_h1_h2(Id::from(0))))], now);
+    assert_eq!(
+        parse_alt_svc_header(
+            r#"h3=":443"; ma=3600; persist=1, h3-29=":443", http%2F1.1="alt.example.net:8443""#,
+            now
+        )
+        .unwrap(),
+        AltSvcUpdate::Alternatives(vec![
+            AltSvc {
+                host: None,
+                port: Some(443),
+                protocol: HttpVersion::H3,
+                expires: Some(now + Duration::from_secs(3600)),
+                persist: true,
+            },
+            AltSvc {
+                host: Some("alt.example.net".to_string()),
+                port: Some(8443),
+                protocol: HttpVersion::H1,
+                expires: Some(now + DEFAULT_ALT_SVC_MAX_AGE),
+                persist: false,
+            },
+        ])
+    );
+    assert_eq!(
+        parse_alt_svc_header("clear", now).unwrap(),
+        AltSvcUpdate::Clear
+    );
+    assert!(parse_alt_svc_header("h3=443", now).is_err());
+    assert!(parse_alt_svc_header(r#"h3=":443"; ma"#, now).is_err());
+
+    let expires = |value: &str| match parse_alt_svc_header(value, now).unwrap() {
+        AltSvcUpdate::Alternatives(alternatives) => alternatives[0].expires,
+        AltSvcUpdate::Clear => unreachable!(),
+    };
+    // A huge `ma` is capped, even beyond the range of an integer.
+    for ma in ["18446744073709551615", "99999999999999999999999"] {
+        assert_eq!(
+            expires(&format!(r#"h3=":443"; ma={ma}"#)),
+            Some(now + MAX_ALT_SVC_MAX_AGE)
+        );
+    }
+    // A non-numeric `ma` is ignored.
+    for ma in ["soon", "-1", "\"1h\""] {
+        assert_eq!(
+            expires(&format!(r#"h3=":443"; ma={ma}"#)),
+            Some(now + DEFAULT_ALT_SVC_MAX_AGE)
+        );
+    }
 }
 
 #[test]
-fn not_url_but_ip() {
-    // Neither of these are a valid URL, but they are valid IP addresses.
-    HappyEyeballs::new("::1", PORT).unwrap();
-    HappyEyeballs::new("127.0.0.1", PORT).unwrap();
+fn alt_svc_frames() {
+    let now = Instant::now();
+    let value = br#"h2="[2001:db8::1]:443""#;
+    let expected = AltSvcUpdate::Alternatives(vec![AltSvc {
+        host: Some("2001:db8::1".to_string()),
+        port: Some(443),
+        protocol: HttpVersion::H2,
+        expires: Some(now + DEFAULT_ALT_SVC_MAX_AGE),
+        persist: false,
+    }]);
+
+    let origin = b"https://example.com";
+    let mut h2 = (origin.len() as u16).to_be_bytes().to_vec();
+    h2.extend_from_slice(origin);
+    h2.extend_from_slice(value);
+    assert_eq!(
+        parse_h2_altsvc_frame(&h2, now).unwrap(),
+        AltSvcFrame {
+            origin: Some("https://example.com".to_string()),
+            update: expected.clone(),
+        }
+    );
+
+    // Sent on a request stream, thus without origin.
+    let mut h3 = vec![0];
+    h3.extend_from_slice(value);
+    assert_eq!(
+        parse_h3_altsvc_frame(&h3, now).unwrap(),
+        AltSvcFrame {
+            origin: None,
+            update: expected,
+        }
+    );
+
+    // Origin length exceeds payload.
+    assert!(parse_h3_altsvc_frame(&[0x40, 0xff], now).is_err());
 }
 
 #[test]
-fn alt_svc_construction() {
-    let now = Instant::now();
-    let config = NetworkConfig {
-        http_versions: HttpVersions::default(),
-        ip: IpPreference::DualStackPreferV6,
+fn expired_alt_svc_dropped() {
+    let expires = Instant::now();
+    let (now, mut he) = setup_with_config(NetworkConfig {
         alt_svc: vec![AltSvc {
             host: None,
             port: None,
             protocol: HttpVersion::H3,
+            expires: Some(expires),
+            persist: false,
         }],
-    };
-    let mut he = HappyEyeballs::new_with_network_config(HOSTNAME, PORT, config).unwrap();
-
-    // Should still send DNS queries as normal
-    he.expect(vec![(None, Some(out_send_dns_https(Id::from(0))))], now);
-}
-
-#[test]
-fn ech_config_propagated_to_endpoint() {
-    let (now, mut he) = setup();
+        ..NetworkConfig::default()
+    });
 
     he.expect(
         vec![
@@ -1043,33 +3760,12 @@ fn ech_config_propagated_to_endpoint() {
             (None, Some(out_send_dns_aaaa(Id::from(1)))),
             (None, Some(out_send_dns_a(Id::from(2)))),
             (
-                Some(in_dns_aaaa_negative(Id::from(1))),
-                Some(out_resolution_delay()),
-            ),
-            (
-                Some(in_dns_a_negative(Id::from(2))),
+                Some(in_dns_https_negative(Id::from(0))),
                 Some(out_resolution_delay()),
             ),
             (
-                Some(Input::DnsResult {
-                    id: Id::from(0),
-                    result: DnsResult::Https(Ok(vec![happy_eyeballs::ServiceInfo {
-                        priority: 1,
-                        target_name: HOSTNAME.into(),
-                        alpn_protocols: HashSet::from([HttpVersion::H3, HttpVersion::H2]),
-                        ipv6_hints: vec![V6_ADDR],
-                        ipv4_hints: vec![],
-                        ech_config: Some(ECH_CONFIG.to_vec()),
-                    }])),
-                }),
-                Some(Output::AttemptConnection {
-                    id: Id::from(3),
-                    endpoint: Endpoint {
-                        address: SocketAddr::new(V6_ADDR.into(), PORT),
-                        protocol: ConnectionAttemptHttpVersions::H3,
-                        ech_config: Some(ECH_CONFIG.to_vec()),
-                    },
-                }),
+                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(out_attempt_v6_h1_h2(Id::from(3))),
             ),
         ],
         now,
@@ -1077,9 +3773,152 @@ fn ech_config_propagated_to_endpoint() {
 }
 
 #[test]
-fn ech_config_from_https_applies_to_aaaa() {
-    let (now, mut he) = setup();
+fn alt_svc_cache() {
+    let now = Instant::now();
+    let origin = Origin {
+        scheme: "https".to_string(),
+        host: HOSTNAME.to_string(),
+        port: PORT,
+    };
+    let mut cache = AltSvcCache::default();
+
+    cache.update(
+        origin.clone(),
+        parse_alt_svc_header(r#"h3=":443"; ma=60; persist=1, h2=":8443"; ma=120"#, now).unwrap(),
+    );
+    assert_eq!(cache.get(&origin, now).len(), 2);
+    assert_eq!(
+        cache.get(&origin, now + Duration::from_secs(90)),
+        vec![AltSvc {
+            host: None,
+            port: Some(8443),
+            protocol: HttpVersion::H2,
+            expires: Some(now + Duration::from_secs(120)),
+            persist: false,
+        }]
+    );
+
+    // Only persistent alternatives survive a network change.
+    cache.on_network_change();
+    assert_eq!(
+        cache
+            .get(&origin, now)
+            .iter()
+            .map(|alt_svc| alt_svc.protocol)
+            .collect::<Vec<_>>(),
+        vec![HttpVersion::H3]
+    );
+
+    cache.remove_expired(now + Duration::from_secs(60));
+    assert_eq!(cache, AltSvcCache::default());
 
+    cache.update(
+        origin.clone(),
+        parse_alt_svc_header(r#"h3=":443""#, now).unwrap(),
+    );
+    cache.update(origin.clone(), parse_alt_svc_header("clear", now).unwrap());
+    assert!(cache.get(&origin, now).is_empty());
+}
+
+#[test]
+fn alt_svc_cache_snapshot() {
+    let now = Instant::now();
+    let wall_now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let origin = Origin {
+        scheme: "https".to_string(),
+        host: HOSTNAME.to_string(),
+        port: PORT,
+    };
+    let mut cache = AltSvcCache::default();
+    cache.update(
+        origin.clone(),
+        parse_alt_svc_header(r#"h3=":443"; ma=60, h2=":8443"; ma=120"#, now).unwrap(),
+    );
+    let snapshot = cache.snapshot(now, wall_now);
+
+    // Restored after a restart, 90 seconds later.
+    let later = Instant::now();
+    let restored = AltSvcCache::restore(snapshot, later, wall_now + Duration::from_secs(90));
+    assert_eq!(
+        restored.get(&origin, later),
+        vec![AltSvc {
+            host: None,
+            port: Some(8443),
+            protocol: HttpVersion::H2,
+            expires: Some(later + Duration::from_secs(30)),
+            persist: false,
+        }]
+    );
+}
+
+/// Expiries overflowing wall-clock time or [`Instant`] are dropped rather than
+/// panicking or never expiring.
+#[test]
+fn alt_svc_cache_snapshot_overflow() {
+    let now = Instant::now();
+    let wall_now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    let origin = Origin {
+        scheme: "https".to_string(),
+        host: HOSTNAME.to_string(),
+        port: PORT,
+    };
+    let alt_svc = |expires| AltSvc {
+        host: None,
+        port: Some(PORT),
+        protocol: HttpVersion::H3,
+        expires,
+        persist: false,
+    };
+    // Within the range of `Instant`, but not of the wall-clock time.
+    let far = Duration::from_secs(i64::MAX as u64 - 100_000_000);
+
+    let mut cache = AltSvcCache::default();
+    cache.update(
+        origin.clone(),
+        AltSvcUpdate::Alternatives(vec![alt_svc(Some(now + far))]),
+    );
+    let snapshot = cache.snapshot(now, wall_now);
+    assert!(
+        AltSvcCache::restore(snapshot, now, wall_now)
+            .get(&origin, now)
+            .is_empty()
+    );
+
+    // A snapshot from a host whose wall-clock time was far off.
+    let mut cache = AltSvcCache::default();
+    cache.update(
+        origin.clone(),
+        AltSvcUpdate::Alternatives(vec![alt_svc(Some(now + Duration::from_secs(60)))]),
+    );
+    let far_off = SystemTime::UNIX_EPOCH + Duration::from_secs(i64::MAX as u64 - 120);
+    let snapshot = cache.snapshot(now, far_off);
+    assert!(
+        AltSvcCache::restore(snapshot, now, SystemTime::UNIX_EPOCH)
+            .get(&origin, now)
+            .is_empty()
+    );
+}
+
+/// Endpoints carry the TargetName they were resolved for and the public name
+/// of their ECH configuration.
+#[test]
+fn endpoint_names() {
+    const PUBLIC_NAME: &str = "public.example.com";
+    let mut ech_config = vec![1, 0x00, 0x20, 0, 32];
+    ech_config.extend([0; 32]);
+    ech_config.extend([0, 4, 0x00, 0x01, 0x00, 0x01, 0, PUBLIC_NAME.len() as u8]);
+    ech_config.extend(PUBLIC_NAME.as_bytes());
+    ech_config.extend([0, 0]);
+    let mut ech_config_list = vec![0xfe, 0x0d];
+    ech_config_list.extend((ech_config.len() as u16).to_be_bytes());
+    ech_config_list.extend(ech_config);
+    let ech_config_list = [
+        (ech_config_list.len() as u16).to_be_bytes().to_vec(),
+        ech_config_list,
+    ]
+    .concat();
+
+    let (now, mut he) = setup();
     he.expect(
         vec![
             (None, Some(out_send_dns_https(Id::from(0)))),
@@ -1090,23 +3929,25 @@ fn ech_config_from_https_applies_to_aaaa() {
                     id: Id::from(0),
                     result: DnsResult::Https(Ok(vec![happy_eyeballs::ServiceInfo {
                         priority: 1,
-                        target_name: HOSTNAME.into(),
-                        alpn_protocols: HashSet::from([HttpVersion::H3, HttpVersion::H2]),
-                        ipv6_hints: vec![],
+                        target_name: "svc1.example.com.".into(),
+                        alpn_protocols: HashSet::from([HttpVersion::H2]),
+                        ipv6_hints: vec![V6_ADDR_2],
                         ipv4_hints: vec![],
-                        ech_config: Some(ECH_CONFIG.to_vec()),
+                        ech_config: Some(ech_config_list.clone()),
                     }])),
                 }),
-                Some(out_resolution_delay()),
+                Some(out_send_dns_svc1(Id::from(3))),
             ),
             (
-                Some(in_dns_aaaa_positive(Id::from(1))),
+                Some(in_dns_aaaa_negative(Id::from(1))),
                 Some(Output::AttemptConnection {
-                    id: Id::from(3),
+                    id: Id::from(4),
                     endpoint: Endpoint {
-                        address: SocketAddr::new(V6_ADDR.into(), PORT),
-                        protocol: ConnectionAttemptHttpVersions::H3,
-                        ech_config: Some(ECH_CONFIG.to_vec()),
+                        ech_config: Some(ech_config_list),
+                        target_name: "svc1.example.com.".into(),
+                        ech_public_name: Some(PUBLIC_NAME.to_string()),
+                        provenance: HINT,
+                        ..endpoint(V6_ADDR_2.into(), ConnectionAttemptHttpVersions::H2)
                     },
                 }),
             ),
@@ -1116,69 +3957,77 @@ fn ech_config_from_https_applies_to_aaaa() {
 }
 
 #[test]
-fn multiple_target_names() {
-    let (now, mut he) = setup();
+fn endpoint_provenance() {
+    let (mut now, mut he) = setup();
 
     he.expect(
         vec![
             (None, Some(out_send_dns_https(Id::from(0)))),
             (None, Some(out_send_dns_aaaa(Id::from(1)))),
             (None, Some(out_send_dns_a(Id::from(2)))),
-            // HTTPS response with a different target name
             (
                 Some(in_dns_https_positive_svc1(Id::from(0))),
                 Some(out_send_dns_svc1(Id::from(3))),
             ),
-            // Now we have queries for both "example.com" and "svc1.example.com."
-            // Getting a positive AAAA for the main host
             (
-                Some(in_dns_aaaa_positive(Id::from(1))),
-                Some(out_attempt_v6_h3(Id::from(4))),
+                None,
+                Some(Output::SendDnsQuery {
+                    id: Id::from(4),
+                    hostname: "svc1.example.com.".into(),
+                    record_type: DnsRecordType::A,
+                }),
             ),
         ],
         now,
     );
-}
+    he.process_input(
+        Input::DnsResult {
+            id: Id::from(3),
+            result: DnsResult::Aaaa(Ok(vec![V6_ADDR_3])),
+        },
+        now,
+    );
+    he.process_input(in_dns_a_positive(Id::from(2)), now);
+    he.process_input(in_dns_aaaa_negative(Id::from(1)), now);
 
-#[test]
-fn alt_svc_used_immediately() {
-    let now = Instant::now();
-    let config = NetworkConfig {
-        http_versions: HttpVersions::default(),
-        ip: IpPreference::DualStackPreferV6,
-        alt_svc: vec![AltSvc {
-            host: None,
-            port: None,
-            protocol: HttpVersion::H3,
-        }],
-    };
-    let mut he = HappyEyeballs::new_with_network_config(HOSTNAME, PORT, config).unwrap();
+    let mut attempted = vec![];
+    while let Some(Output::AttemptConnection { endpoint, .. }) = he.process_output(now) {
+        attempted.push((
+            endpoint.address.ip(),
+            endpoint.protocol,
+            endpoint.provenance.source,
+            endpoint.provenance.service_priority,
+        ));
+        now += CONNECTION_ATTEMPT_DELAY;
+    }
 
-    // Alt-svc with H3 should make H3 available even without HTTPS DNS response
-    he.expect(
-        vec![
-            (None, Some(out_send_dns_https(Id::from(0)))),
-            (None, Some(out_send_dns_aaaa(Id::from(1)))),
-            (None, Some(out_send_dns_a(Id::from(2)))),
+    assert_eq!(
+        attempted[..3],
+        [
             (
-                Some(in_dns_https_negative(Id::from(0))),
-                Some(out_resolution_delay()),
+                V4_ADDR.into(),
+                ConnectionAttemptHttpVersions::H3,
+                EndpointSource::DnsAnswer,
+                None
             ),
-            // Alt-svc provided H3, so we should attempt H3 connection
             (
-                Some(in_dns_aaaa_positive(Id::from(1))),
-                Some(out_attempt_v6_h3(Id::from(3))),
+                V6_ADDR_3.into(),
+                ConnectionAttemptHttpVersions::H3,
+                EndpointSource::DnsAnswer,
+                Some(1)
             ),
-        ],
-        now,
+            (
+                V4_ADDR.into(),
+                ConnectionAttemptHttpVersions::H2,
+                EndpointSource::DnsAnswer,
+                None
+            ),
+        ]
     );
 }
 
-/// Website with HTTPS record with `noDefaultAlpn` set.
-///
-/// See e.g. <adamwoodland.com>.
 #[test]
-fn no_default_alpn() {
+fn hints_reconciled_per_target_name() {
     let (now, mut he) = setup();
 
     he.expect(
@@ -1187,34 +4036,71 @@ fn no_default_alpn() {
             (None, Some(out_send_dns_aaaa(Id::from(1)))),
             (None, Some(out_send_dns_a(Id::from(2)))),
             (
-                Some(in_dns_https_positive_h2_h3(Id::from(0))),
-                Some(out_resolution_delay()),
-            ),
-            (
-                Some(in_dns_aaaa_positive(Id::from(1))),
-                Some(out_attempt_v6_h3(Id::from(3))),
-            ),
-            (
-                Some(in_dns_a_positive(Id::from(2))),
-                Some(out_connection_attempt_delay()),
-            ),
-            (
-                Some(in_connection_result_negative(Id::from(3))),
-                Some(out_attempt_v4_h3(Id::from(4))),
-            ),
-            (
-                Some(in_connection_result_negative(Id::from(4))),
-                Some(out_attempt_v6_h2(Id::from(5))),
-            ),
-            (
-                Some(in_connection_result_negative(Id::from(5))),
-                Some(out_attempt_v4_h2(Id::from(6))),
+                Some(in_dns_https_positive_svc1(Id::from(0))),
+                Some(out_send_dns_svc1(Id::from(3))),
             ),
             (
-                Some(in_connection_result_negative(Id::from(6))),
-                Some(Output::Failed),
+                None,
+                Some(Output::SendDnsQuery {
+                    id: Id::from(4),
+                    hostname: "svc1.example.com.".into(),
+                    record_type: DnsRecordType::A,
+                }),
             ),
         ],
         now,
     );
+
+    // The AAAA records of "svc1.example.com." differ from its hints.
+    he.process_input(
+        Input::DnsResult {
+            id: Id::from(3),
+            result: DnsResult::Aaaa(Ok(vec![V6_ADDR_3])),
+        },
+        now,
+    );
+    he.process_input(in_dns_a_negative(Id::from(4)), now);
+    he.process_input(in_dns_aaaa_negative(Id::from(1)), now);
+    he.process_input(in_dns_a_negative(Id::from(2)), now);
+
+    let svc1 = |ip: Ipv6Addr, provenance| Endpoint {
+        address: SocketAddr::new(ip.into(), PORT),
+        target_name: "svc1.example.com.".into(),
+        provenance,
+        ..endpoint(ip.into(), ConnectionAttemptHttpVersions::H3)
+    };
+    let answer = Provenance {
+        source: EndpointSource::DnsAnswer,
+        service_priority: Some(1),
+    };
+    he.expect(
+        vec![(
+            None,
+            Some(Output::AttemptConnection {
+                id: Id::from(5),
+                endpoint: svc1(V6_ADDR_3, answer),
+            }),
+        )],
+        now,
+    );
+
+    let mut attempted = vec![];
+    let mut now = now;
+    while let Some(output) = he.process_output(now) {
+        if let Output::AttemptConnection { endpoint, .. } = output {
+            attempted.push(endpoint);
+        }
+        now += CONNECTION_ATTEMPT_DELAY;
+    }
+    // The hint-only address is attempted after all authoritative ones.
+    let hint = attempted
+        .iter()
+        .position(|e| e.provenance.source == EndpointSource::AddressHint)
+        .unwrap();
+    assert_eq!(attempted[hint], svc1(V6_ADDR_2, HINT));
+    assert!(
+        attempted[hint..]
+            .iter()
+            .all(|e| e.provenance.source == EndpointSource::AddressHint)
+    );
 }